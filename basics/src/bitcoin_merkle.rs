@@ -0,0 +1,481 @@
+use std::fmt;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256dHasher};
+use crate::merkle_tree::{Hash, HashDomain, MerkleError, MerkleNode, MerkleTree, OddNodePolicy, TreeConfig};
+
+/// Most transactions a block can hold (max block weight / min transaction weight)
+pub const MAX_BLOCK_TRANSACTIONS: u32 = 4_000_000 / 240;
+
+/// Txid or block hash as displayed by Bitcoin tools: byte-reversed hex
+pub fn txid_to_hex(hash: &Hash) -> String {
+    let mut reversed = *hash;
+    reversed.reverse();
+    hex::encode(reversed)
+}
+
+/// Parse a displayed txid or block hash back into internal byte order
+pub fn txid_from_hex(hex_str: &str) -> Option<Hash> {
+    let mut hash: Hash = hex::decode(hex_str).ok()?.try_into().ok()?;
+    hash.reverse();
+    Some(hash)
+}
+
+impl MerkleTree<Sha256dHasher> {
+    /// Transaction tree of a Bitcoin block
+    ///
+    /// The txids (internal byte order) are the leaves as they are, nodes are the
+    /// double SHA-256 of `left || right`, and an odd node is paired with itself.
+    pub fn bitcoin(txids: &[Hash]) -> Result<Self, MerkleError> {
+        let config = TreeConfig { odd_policy: OddNodePolicy::DuplicateLast, domain: HashDomain::Plain, arity: 2 };
+        Self::from_leaf_hashes(txids.to_vec(), config)
+    }
+
+    /// True if two real siblings are equal somewhere in the tree (CVE-2012-2459)
+    ///
+    /// Duplicating the last nodes of a layer gives the same root as the original
+    /// list, so a block with such a pair must be rejected, not marked invalid for good.
+    pub fn is_mutated(&self) -> bool {
+        (0..self.depth()).any(|level| {
+            self.layer(level).unwrap().chunks_exact(2).any(|pair| pair[0] == pair[1])
+        })
+    }
+}
+
+/// An 80-byte Bitcoin block header, hashes in internal byte order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block: Hash,
+    pub merkle_root: Hash,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Serialized header, integers little-endian
+    pub fn to_bytes(&self) -> [u8; 80] {
+        let mut bytes = [0u8; 80];
+        bytes[..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_block);
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[68..72].copy_from_slice(&self.time.to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[76..].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; 80]) -> Self {
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        BlockHeader {
+            version: u32_at(0) as i32,
+            prev_block: bytes[4..36].try_into().unwrap(),
+            merkle_root: bytes[36..68].try_into().unwrap(),
+            time: u32_at(68),
+            bits: u32_at(72),
+            nonce: u32_at(76),
+        }
+    }
+
+    /// Block hash: double SHA-256 of the header
+    pub fn hash(&self) -> Hash {
+        Sha256dHasher::hash(&[&self.to_bytes()])
+    }
+
+    /// True if the block hash is at or below the target encoded in `bits`
+    pub fn check_proof_of_work(&self) -> bool {
+        let Some(target) = target_from_bits(self.bits) else { return false; };
+        let mut hash = self.hash();
+        //the hash is a little-endian number, compare big-endian
+        hash.reverse();
+        hash <= target
+    }
+}
+
+//Compact `bits` to a 256-bit big-endian target, None if negative, zero or overflowing
+fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 || mantissa == 0 || exponent > 32 { return None; }
+    let mut target = [0u8; 32];
+    if exponent <= 3 {
+        target[28..].copy_from_slice(&(mantissa >> (8 * (3 - exponent))).to_be_bytes());
+    } else {
+        target[32 - exponent..35 - exponent].copy_from_slice(&mantissa.to_be_bytes()[1..]);
+    }
+    Some(target)
+}
+
+/// Why a partial Merkle tree was rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartialMerkleError {
+    NoTransactions,
+    TooManyTransactions(u32),
+    /// More hashes than transactions, or fewer flag bits than hashes
+    TooManyHashes,
+    /// The traversal needed more flag bits or hashes than given
+    OutOfFlags,
+    OutOfHashes,
+    /// The traversal ended before consuming every flag byte or hash
+    UnusedFlags,
+    UnusedHashes,
+    /// Two computed siblings are equal (CVE-2012-2459)
+    DuplicateSibling { height: usize, position: usize },
+    /// The serialized tree is truncated, not canonical or followed by extra bytes
+    Malformed,
+    /// The computed root is not the one of the block header
+    RootMismatch,
+}
+
+impl fmt::Display for PartialMerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartialMerkleError::NoTransactions => write!(f, "the tree has no transactions"),
+            PartialMerkleError::TooManyTransactions(count) => write!(f, "{count} transactions cannot fit in a block"),
+            PartialMerkleError::TooManyHashes => write!(f, "more hashes than transactions or flag bits"),
+            PartialMerkleError::OutOfFlags => write!(f, "ran out of flag bits"),
+            PartialMerkleError::OutOfHashes => write!(f, "ran out of hashes"),
+            PartialMerkleError::UnusedFlags => write!(f, "flag bytes left unused"),
+            PartialMerkleError::UnusedHashes => write!(f, "hashes left unused"),
+            PartialMerkleError::DuplicateSibling { height, position } => write!(f, "node {position} at height {height} equals its sibling"),
+            PartialMerkleError::Malformed => write!(f, "malformed partial merkle tree"),
+            PartialMerkleError::RootMismatch => write!(f, "computed root does not match the block header"),
+        }
+    }
+}
+
+impl std::error::Error for PartialMerkleError {}
+
+/// Partial Merkle tree of a `merkleblock` message (BIP37)
+///
+/// A depth-first walk from the root: one flag bit per visited node, set if a matched
+/// transaction is beneath it, and one hash per node whose subtree is not descended
+/// (every leaf reached is given by its txid).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<Hash>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Partial tree proving the transactions whose `matches` entry is true
+    ///
+    /// Panics if `txids` is empty or `matches` doesn't have one entry per txid.
+    pub fn build(txids: &[Hash], matches: &[bool]) -> Self {
+        if txids.len() != matches.len() {panic!("Every txid needs a match flag!")}
+        let tree = MerkleTree::bitcoin(txids).unwrap_or_else(|e| panic!("{e}"));
+        let mut partial = PartialMerkleTree { total_transactions: txids.len() as u32, hashes: Vec::new(), flags: Vec::new() };
+        partial.traverse_and_build(&tree, matches, tree.depth(), 0);
+        partial
+    }
+
+    fn traverse_and_build(&mut self, tree: &MerkleTree<Sha256dHasher>, matches: &[bool], height: usize, position: usize) {
+        let first = position << height;
+        let last = matches.len().min((position + 1) << height);
+        let parent_of_match = matches[first..last].contains(&true);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            self.hashes.push(tree.node(height, position).unwrap());
+            return;
+        }
+        self.traverse_and_build(tree, matches, height - 1, 2 * position);
+        if 2 * position + 1 < self.width(height - 1) {
+            self.traverse_and_build(tree, matches, height - 1, 2 * position + 1);
+        }
+    }
+
+    /// Recompute the root and return it with the matched `(index, txid)` pairs
+    pub fn extract_matches(&self) -> Result<(Hash, Vec<(usize, Hash)>), PartialMerkleError> {
+        if self.total_transactions == 0 { return Err(PartialMerkleError::NoTransactions); }
+        if self.total_transactions > MAX_BLOCK_TRANSACTIONS { return Err(PartialMerkleError::TooManyTransactions(self.total_transactions)); }
+        if self.hashes.len() > self.total_transactions as usize || self.flags.len() < self.hashes.len() {
+            return Err(PartialMerkleError::TooManyHashes);
+        }
+        let mut height = 0;
+        while self.width(height) > 1 { height += 1 }
+
+        let (mut flags_used, mut hashes_used, mut matches) = (0, 0, Vec::new());
+        let root = self.traverse_and_extract(height, 0, &mut flags_used, &mut hashes_used, &mut matches)?;
+        //the flags were sent as whole bytes
+        if flags_used.div_ceil(8) != self.flags.len().div_ceil(8) { return Err(PartialMerkleError::UnusedFlags); }
+        if hashes_used != self.hashes.len() { return Err(PartialMerkleError::UnusedHashes); }
+        Ok((root, matches))
+    }
+
+    fn traverse_and_extract(&self, height: usize, position: usize, flags_used: &mut usize, hashes_used: &mut usize, matches: &mut Vec<(usize, Hash)>) -> Result<Hash, PartialMerkleError> {
+        let parent_of_match = *self.flags.get(*flags_used).ok_or(PartialMerkleError::OutOfFlags)?;
+        *flags_used += 1;
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(*hashes_used).ok_or(PartialMerkleError::OutOfHashes)?;
+            *hashes_used += 1;
+            if height == 0 && parent_of_match { matches.push((position, hash)) }
+            return Ok(hash);
+        }
+        let left = self.traverse_and_extract(height - 1, 2 * position, flags_used, hashes_used, matches)?;
+        let right = if 2 * position + 1 < self.width(height - 1) {
+            let right = self.traverse_and_extract(height - 1, 2 * position + 1, flags_used, hashes_used, matches)?;
+            if right == left { return Err(PartialMerkleError::DuplicateSibling { height: height - 1, position: 2 * position + 1 }); }
+            right
+        } else {
+            left
+        };
+        Ok(MerkleNode::<Sha256dHasher>::parent_with(&MerkleNode { hash: left }, &MerkleNode { hash: right }, HashDomain::Plain).hash)
+    }
+
+    //Number of nodes at `height` (0 = transactions)
+    fn width(&self, height: usize) -> usize {
+        (self.total_transactions as usize).div_ceil(1 << height)
+    }
+
+    /// Wire format: `total (u32 LE) | count | hashes | count | flag bytes`
+    ///
+    /// Counts are CompactSize integers and flag bits are packed LSB first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.total_transactions.to_le_bytes().to_vec();
+        write_compact_size(&mut bytes, self.hashes.len() as u64);
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash);
+        }
+        let mut flag_bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, flag) in self.flags.iter().enumerate() {
+            flag_bytes[i / 8] |= (*flag as u8) << (i % 8);
+        }
+        write_compact_size(&mut bytes, flag_bytes.len() as u64);
+        bytes.extend_from_slice(&flag_bytes);
+        bytes
+    }
+
+    /// Parse the wire format; the tree itself is checked by `extract_matches`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PartialMerkleError> {
+        let (total, mut rest) = bytes.split_first_chunk::<4>().ok_or(PartialMerkleError::Malformed)?;
+        let hash_count = read_compact_size(&mut rest)?;
+        let hash_bytes = rest.split_off(..hash_count.checked_mul(32).ok_or(PartialMerkleError::Malformed)?).ok_or(PartialMerkleError::Malformed)?;
+        let flag_count = read_compact_size(&mut rest)?;
+        let flag_bytes = rest.split_off(..flag_count).ok_or(PartialMerkleError::Malformed)?;
+        if !rest.is_empty() { return Err(PartialMerkleError::Malformed); }
+        Ok(PartialMerkleTree {
+            total_transactions: u32::from_le_bytes(*total),
+            hashes: hash_bytes.chunks_exact(32).map(|hash| hash.try_into().unwrap()).collect(),
+            flags: (0..8 * flag_bytes.len()).map(|i| flag_bytes[i / 8] >> (i % 8) & 1 == 1).collect(),
+        })
+    }
+}
+
+/// Check a `merkleblock` (header + partial tree) and return its matched `(index, txid)` pairs
+pub fn verify_merkleblock(header: &BlockHeader, partial: &PartialMerkleTree) -> Result<Vec<(usize, Hash)>, PartialMerkleError> {
+    let (root, matches) = partial.extract_matches()?;
+    if root != header.merkle_root { return Err(PartialMerkleError::RootMismatch); }
+    Ok(matches)
+}
+
+fn write_compact_size(bytes: &mut Vec<u8>, value: u64) {
+    match value {
+        0..0xfd => bytes.push(value as u8),
+        0xfd..=0xffff => {
+            bytes.push(0xfd);
+            bytes.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            bytes.push(0xfe);
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+//Read a CompactSize off the front of `bytes`, only in its shortest form
+fn read_compact_size(bytes: &mut &[u8]) -> Result<usize, PartialMerkleError> {
+    let prefix = *bytes.split_off_first().ok_or(PartialMerkleError::Malformed)?;
+    let (len, min) = match prefix {
+        0xfd => (2, 0xfd),
+        0xfe => (4, 0x1_0000),
+        0xff => (8, 0x1_0000_0000),
+        small => return Ok(small as usize),
+    };
+    let raw = bytes.split_off(..len).ok_or(PartialMerkleError::Malformed)?;
+    let value = raw.iter().rev().fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+    if value < min { return Err(PartialMerkleError::Malformed); }
+    usize::try_from(value).map_err(|_| PartialMerkleError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(hex_str: &str) -> Hash {
+        txid_from_hex(hex_str).unwrap()
+    }
+
+    // Mainnet block 100000
+    const BLOCK_100000_TXIDS: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+
+    fn block_100000() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block: txid("000000000002d01c1fccc21636b607dfd930d31d01c3a62104612a1719011250"),
+            merkle_root: txid("f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"),
+            time: 1293623863,
+            bits: 0x1b04864c,
+            nonce: 274148111,
+        }
+    }
+
+    fn genesis() -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_block: [0; 32],
+            merkle_root: txid("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"),
+            time: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+        }
+    }
+
+    fn synthetic_txids(count: usize) -> Vec<Hash> {
+        (0..count).map(|i| Sha256dHasher::hash(&[format!("tx_{}", i).as_bytes()])).collect()
+    }
+
+    #[test]
+    fn test_mainnet_headers() {
+        for (header, hash) in [
+            (genesis(), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            (block_100000(), "000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506"),
+        ] {
+            assert_eq!(txid_to_hex(&header.hash()), hash);
+            assert!(header.check_proof_of_work());
+            assert_eq!(BlockHeader::from_bytes(&header.to_bytes()), header);
+            let weaker = BlockHeader { nonce: header.nonce + 1, ..header };
+            assert!(!weaker.check_proof_of_work());
+        }
+    }
+
+    #[test]
+    fn test_block_merkle_roots() {
+        let txids: Vec<Hash> = BLOCK_100000_TXIDS.iter().map(|hex_str| txid(hex_str)).collect();
+        let tree = MerkleTree::bitcoin(&txids).unwrap();
+        assert_eq!(tree.root(), block_100000().merkle_root);
+        assert!(!tree.is_mutated());
+
+        // A single transaction is its own root
+        let genesis_tree = MerkleTree::bitcoin(&[genesis().merkle_root]).unwrap();
+        assert_eq!(genesis_tree.root(), genesis().merkle_root);
+        assert!(MerkleTree::bitcoin(&[]).is_err());
+    }
+
+    #[test]
+    fn test_odd_layers_duplicate_last() {
+        let txids = synthetic_txids(3);
+        let node = |left: &Hash, right: &Hash| Sha256dHasher::hash(&[left, right]);
+        let expected = node(&node(&txids[0], &txids[1]), &node(&txids[2], &txids[2]));
+        assert_eq!(MerkleTree::bitcoin(&txids).unwrap().root(), expected);
+    }
+
+    #[test]
+    fn test_mutation_detected() {
+        let txids = synthetic_txids(3);
+        let tree = MerkleTree::bitcoin(&txids).unwrap();
+        let mut mutated = txids.clone();
+        mutated.push(txids[2]);
+        let mutated_tree = MerkleTree::bitcoin(&mutated).unwrap();
+        assert_eq!(mutated_tree.root(), tree.root());
+        assert!(mutated_tree.is_mutated());
+        assert!(!tree.is_mutated());
+
+        // Duplicating a whole subtree (6 -> 8 leaves works the same way one level up)
+        let six = synthetic_txids(6);
+        let mut eight = six.clone();
+        eight.extend_from_slice(&six[4..6]);
+        assert_eq!(MerkleTree::bitcoin(&eight).unwrap().root(), MerkleTree::bitcoin(&six).unwrap().root());
+        assert!(MerkleTree::bitcoin(&eight).unwrap().is_mutated());
+    }
+
+    #[test]
+    fn test_partial_tree_round_trip() {
+        let txids: Vec<Hash> = BLOCK_100000_TXIDS.iter().map(|hex_str| txid(hex_str)).collect();
+        let partial = PartialMerkleTree::build(&txids, &[false, true, false, false]);
+        // Root, left subtree, both leaves of the left subtree, right subtree
+        assert_eq!(partial.flags, vec![true, true, false, true, false]);
+        assert_eq!(partial.hashes.len(), 3);
+
+        let bytes = partial.to_bytes();
+        assert_eq!(bytes.len(), 4 + 1 + 3 * 32 + 1 + 1);
+        let decoded = PartialMerkleTree::from_bytes(&bytes).unwrap();
+        assert_eq!(verify_merkleblock(&block_100000(), &decoded), Ok(vec![(1, txids[1])]));
+        assert_eq!(verify_merkleblock(&genesis(), &decoded), Err(PartialMerkleError::RootMismatch));
+    }
+
+    #[test]
+    fn test_partial_tree_all_match_patterns() {
+        for count in [1, 2, 3, 5, 7, 8, 13] {
+            let txids = synthetic_txids(count);
+            let root = MerkleTree::bitcoin(&txids).unwrap().root();
+            for pattern in 0..1u32 << count.min(8) {
+                let matches: Vec<bool> = (0..count).map(|i| pattern >> (i % 8) & 1 == 1).collect();
+                let partial = PartialMerkleTree::from_bytes(&PartialMerkleTree::build(&txids, &matches).to_bytes()).unwrap();
+                let (extracted_root, found) = partial.extract_matches().unwrap();
+                assert_eq!(extracted_root, root, "{count} txids, pattern {pattern:b}");
+                let expected: Vec<(usize, Hash)> = (0..count).filter(|i| matches[*i]).map(|i| (i, txids[i])).collect();
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_partial_trees_rejected() {
+        let txids = synthetic_txids(5);
+        let partial = PartialMerkleTree::build(&txids, &[false, false, true, false, false]);
+        assert!(partial.extract_matches().is_ok());
+
+        let mut extra_hash = partial.clone();
+        extra_hash.hashes.push([0; 32]);
+        assert_eq!(extra_hash.extract_matches().err(), Some(PartialMerkleError::UnusedHashes));
+        let mut missing_hash = partial.clone();
+        missing_hash.hashes.pop();
+        assert_eq!(missing_hash.extract_matches().err(), Some(PartialMerkleError::OutOfHashes));
+        let mut extra_byte = partial.clone();
+        extra_byte.flags.extend([false; 8]);
+        assert_eq!(extra_byte.extract_matches().err(), Some(PartialMerkleError::UnusedFlags));
+        let empty = PartialMerkleTree { total_transactions: 0, hashes: vec![], flags: vec![] };
+        assert_eq!(empty.extract_matches().err(), Some(PartialMerkleError::NoTransactions));
+        let huge = PartialMerkleTree { total_transactions: u32::MAX, ..partial.clone() };
+        assert_eq!(huge.extract_matches().err(), Some(PartialMerkleError::TooManyTransactions(u32::MAX)));
+
+        let bytes = partial.to_bytes();
+        assert_eq!(PartialMerkleTree::from_bytes(&bytes[..bytes.len() - 1]), Err(PartialMerkleError::Malformed));
+        assert_eq!(PartialMerkleTree::from_bytes(&[bytes.as_slice(), &[0]].concat()), Err(PartialMerkleError::Malformed));
+        // Hash count 3 written as 0xfd 0x03 0x00
+        let mut long_count = bytes[..4].to_vec();
+        long_count.extend_from_slice(&[0xfd, partial.hashes.len() as u8, 0]);
+        long_count.extend_from_slice(&bytes[5..]);
+        assert_eq!(PartialMerkleTree::from_bytes(&long_count), Err(PartialMerkleError::Malformed));
+    }
+
+    #[test]
+    fn test_duplicated_siblings_rejected() {
+        // The mutated 4-leaf list from a 3-leaf block: both right leaves are equal
+        let txids = synthetic_txids(3);
+        let mutated = [txids[0], txids[1], txids[2], txids[2]];
+        let partial = PartialMerkleTree::build(&mutated, &[false, false, true, true]);
+        assert_eq!(partial.extract_matches().err(), Some(PartialMerkleError::DuplicateSibling { height: 0, position: 3 }));
+    }
+
+    #[test]
+    fn test_compact_size() {
+        for value in [0, 0xfc, 0xfd, 0xffff, 0x1_0000, 0xffff_ffff, 0x1_0000_0000] {
+            let mut bytes = Vec::new();
+            write_compact_size(&mut bytes, value);
+            let mut rest = bytes.as_slice();
+            assert_eq!(read_compact_size(&mut rest), Ok(value as usize));
+            assert!(rest.is_empty());
+        }
+    }
+}
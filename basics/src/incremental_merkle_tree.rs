@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{HashDomain, MerkleNode, SiblingDirection};
+use crate::root_history::{verify_proof_recent, RootHistory, ROOT_HISTORY_SIZE};
+use crate::sparse_nodes::zero_hashes;
+
+/// Why `IncrementalMerkleTree::witness` has no path to give
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WitnessError {
+    /// No leaf was inserted at this index yet
+    OutOfRange { leaf_index: usize, len: usize },
+    /// The leaf was inserted with `insert` (or untracked): its siblings are gone
+    NotTracked(usize),
+}
+
+impl fmt::Display for WitnessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WitnessError::OutOfRange { leaf_index, len } => write!(f, "leaf {leaf_index} out of range for {len} leaves"),
+            WitnessError::NotTracked(leaf_index) => write!(f, "leaf {leaf_index} was not inserted with insert_tracked"),
+        }
+    }
+}
+
+impl std::error::Error for WitnessError {}
+
+/// Append-only Merkle tree of fixed depth (Tornado Cash style)
+///
+/// Only the `filled_subtrees` frontier and the zero hashes are kept, so the tree
+/// costs O(depth) memory and O(depth) hashes per insert. Empty slots hold the empty
+/// leaf (`b""`). Witnesses are opt-in: leaves inserted with `insert_tracked` get an
+/// O(depth) tracker updated on every insert, and their witnesses verify with `verify_proof`.
+/// The last roots are kept in a `RootHistory`, so slightly stale witnesses stay valid.
+pub struct IncrementalMerkleTree<H: MerkleHasher = Sha256Hasher> {
+    depth: usize,
+    domain: HashDomain,
+    //zeros[l] = root of an empty subtree of height l
+    zeros: Vec<H::Digest>,
+    //filled_subtrees[l] = last left node seen at level l
+    filled_subtrees: Vec<H::Digest>,
+    root: H::Digest,
+    //number of inserted leaves
+    len: usize,
+    //recent roots, the current one included
+    history: RootHistory<H::Digest>,
+    //leaf index -> tracker of its witness
+    tracked: BTreeMap<usize, WitnessTracker<H::Digest>>,
+}
+
+//Witness of one leaf, completed as the leaves after it are inserted
+//
+//The right siblings of a leaf cover consecutive ranges of the next leaves, lowest
+//level first, so only the one being filled needs a frontier of its own.
+struct WitnessTracker<D> {
+    //siblings[l], once known for good: left siblings from the start, right ones when full
+    siblings: Vec<Option<D>>,
+    //level of the right sibling being filled (depth if none), with its frontier, size and root
+    cursor_level: usize,
+    cursor_frontier: Vec<D>,
+    cursor_len: usize,
+    cursor_root: D,
+}
+
+impl IncrementalMerkleTree {
+    /// Empty SHA-256 tree with room for 2^depth leaves
+    pub fn new(depth: usize) -> Self {
+        Self::build(depth, HashDomain::Plain)
+    }
+}
+
+impl<H: MerkleHasher> IncrementalMerkleTree<H> {
+    /// Empty tree with any hasher and hash domain, keeping `ROOT_HISTORY_SIZE` roots
+    pub fn build(depth: usize, domain: HashDomain) -> Self {
+        Self::build_with_history(depth, domain, ROOT_HISTORY_SIZE)
+    }
+
+    /// Same as `build`, keeping the last `history_size` roots (the empty root included)
+    pub fn build_with_history(depth: usize, domain: HashDomain, history_size: usize) -> Self {
+        if depth >= usize::BITS as usize {panic!("The depth must be below {}!", usize::BITS)}
+        let zeros = zero_hashes::<H>(depth, domain);
+        let mut history = RootHistory::new(history_size);
+        history.push(zeros[depth]);
+        IncrementalMerkleTree {
+            depth,
+            domain,
+            filled_subtrees: zeros[..depth].to_vec(),
+            root: zeros[depth],
+            zeros,
+            len: 0,
+            history,
+            tracked: BTreeMap::new(),
+        }
+    }
+
+    /// Append a leaf and return its index
+    ///
+    /// The leaf can't be witnessed later: only the frontier is kept, so its siblings
+    /// are lost. Use `insert_tracked` for leaves that need a witness.
+    pub fn insert(&mut self, leaf: &[u8]) -> usize {
+        if self.len() == self.capacity() {panic!("The tree is full!")}
+        let index = self.len;
+        let hash = MerkleNode::<H>::leaf_with(leaf, self.domain).hash;
+        self.root = append::<H>(&mut self.filled_subtrees, &self.zeros, index, hash, self.domain);
+        self.len += 1;
+        self.history.push(self.root);
+        for tracker in self.tracked.values_mut() {
+            tracker.append::<H>(hash, &self.zeros, self.domain);
+        }
+        index
+    }
+
+    /// Same as `insert`, keeping the witness of this leaf up to date from now on
+    pub fn insert_tracked(&mut self, leaf: &[u8]) -> usize {
+        let index = self.insert(leaf);
+        //after the insert, the frontier holds the left siblings of the new leaf
+        let siblings: Vec<Option<H::Digest>> = (0..self.depth)
+            .map(|level| (index >> level & 1 == 1).then_some(self.filled_subtrees[level]))
+            .collect();
+        let mut tracker = WitnessTracker {
+            siblings,
+            cursor_level: 0,
+            cursor_frontier: Vec::new(),
+            cursor_len: 0,
+            cursor_root: self.zeros[0],
+        };
+        tracker.next_cursor(0, &self.zeros);
+        self.tracked.insert(index, tracker);
+        index
+    }
+
+    /// Stop tracking the witness of leaf `leaf_index`, false if it wasn't tracked
+    pub fn untrack(&mut self, leaf_index: usize) -> bool {
+        self.tracked.remove(&leaf_index).is_some()
+    }
+
+    /// Return the current root
+    pub fn root(&self) -> H::Digest {
+        self.root
+    }
+
+    /// Return the recent roots, latest first through `RootHistory::iter`
+    pub fn root_history(&self) -> &RootHistory<H::Digest> {
+        &self.history
+    }
+
+    /// Return true if `root` is the current root or one of the recent ones
+    pub fn is_known_root(&self, root: &H::Digest) -> bool {
+        self.history.is_known_root(root)
+    }
+
+    /// Check a witness against any known root, not only the current one
+    pub fn verify_recent(&self, leaf: &[u8], witness: &[(H::Digest, SiblingDirection)]) -> bool {
+        verify_proof_recent::<H>(leaf, witness, &self.history, self.domain)
+    }
+
+    /// Return the fixed depth of the tree
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Return the number of inserted leaves
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the maximum number of leaves (2^depth)
+    pub fn capacity(&self) -> usize {
+        1 << self.depth
+    }
+
+    /// Return the root of an empty subtree of height `level`
+    pub fn zero_hash(&self, level: usize) -> Option<H::Digest> {
+        self.zeros.get(level).copied()
+    }
+
+    /// Authentication path of a tracked leaf against the current root
+    ///
+    /// Always `depth()` siblings long, empty subtrees are filled with zero hashes.
+    /// Only leaves inserted with `insert_tracked` (and not untracked since) have one:
+    /// the tree keeps no other siblings, so any other leaf gives `WitnessError::NotTracked`.
+    pub fn witness(&self, leaf_index: usize) -> Result<Vec<(H::Digest, SiblingDirection)>, WitnessError> {
+        if leaf_index >= self.len { return Err(WitnessError::OutOfRange { leaf_index, len: self.len }); }
+        let tracker = self.tracked.get(&leaf_index).ok_or(WitnessError::NotTracked(leaf_index))?;
+        let path = (0..self.depth)
+            .map(|level| {
+                let sibling = match tracker.siblings[level] {
+                    Some(hash) => hash,
+                    None if level == tracker.cursor_level => tracker.cursor_root,
+                    None => self.zeros[level],
+                };
+                let direction = if leaf_index >> level & 1 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+                (sibling, direction)
+            })
+            .collect();
+        Ok(path)
+    }
+}
+
+impl<D: Copy> WitnessTracker<D> {
+    //Add the next leaf to the right sibling being filled
+    fn append<H: MerkleHasher<Digest = D>>(&mut self, hash: D, zeros: &[D], domain: HashDomain) {
+        if self.cursor_level == self.siblings.len() { return; }
+        self.cursor_root = append::<H>(&mut self.cursor_frontier, zeros, self.cursor_len, hash, domain);
+        self.cursor_len += 1;
+        if self.cursor_len == 1 << self.cursor_level {
+            self.siblings[self.cursor_level] = Some(self.cursor_root);
+            self.next_cursor(self.cursor_level + 1, zeros);
+        }
+    }
+
+    //Move the cursor to the first unknown (right) sibling from `level` up
+    fn next_cursor(&mut self, level: usize, zeros: &[D]) {
+        self.cursor_level = (level..self.siblings.len()).find(|l| self.siblings[*l].is_none()).unwrap_or(self.siblings.len());
+        self.cursor_frontier = zeros[..self.cursor_level].to_vec();
+        self.cursor_len = 0;
+        self.cursor_root = zeros[self.cursor_level];
+    }
+}
+
+//Insert leaf `index` into a subtree of height `frontier.len()` and return its new root
+fn append<H: MerkleHasher>(frontier: &mut [H::Digest], zeros: &[H::Digest], index: usize, hash: H::Digest, domain: HashDomain) -> H::Digest {
+    let mut current = MerkleNode::<H> { hash };
+    let mut curr_idx = index;
+    for (level, filled) in frontier.iter_mut().enumerate() {
+        let (left, right) = if curr_idx % 2 == 1 {
+            (MerkleNode { hash: *filled }, current)
+        } else {
+            //new left node: its right sibling is still empty
+            *filled = current.hash;
+            (current, MerkleNode { hash: zeros[level] })
+        };
+        current = MerkleNode::parent_with(&left, &right, domain);
+        curr_idx /= 2;
+    }
+    current.hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Keccak256Hasher;
+    use crate::merkle_tree::{verify_proof, verify_proof_with, MerkleTree, OddNodePolicy};
+    use sha2::{Digest, Sha256};
+
+    // Same commitment as `hash_note` in week1_sha2_hashing.rs
+    fn commitment(secret: u64, nullifier: u64) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(secret.to_le_bytes());
+        hasher.update(nullifier.to_le_bytes());
+        hasher.finalize().into()
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let tree = IncrementalMerkleTree::new(4);
+        assert!(tree.is_empty());
+        assert_eq!(tree.capacity(), 16);
+        assert_eq!(tree.root(), tree.zero_hash(4).unwrap());
+        assert_eq!(tree.witness(0), Err(WitnessError::OutOfRange { leaf_index: 0, len: 0 }));
+    }
+
+    #[test]
+    fn test_insert_returns_indices() {
+        let mut tree = IncrementalMerkleTree::new(3);
+        for i in 0..8 {
+            assert_eq!(tree.insert(&commitment(i, i + 100)), i as usize);
+        }
+        assert_eq!(tree.len(), 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_full_tree_panics() {
+        let mut tree = IncrementalMerkleTree::new(1);
+        tree.insert(b"a");
+        tree.insert(b"b");
+        tree.insert(b"c");
+    }
+
+    #[test]
+    fn test_root_matches_padded_merkle_tree() {
+        let depth = 4;
+        let notes: Vec<[u8; 32]> = (0..11).map(|i| commitment(i, 7 * i)).collect();
+        let mut tree = IncrementalMerkleTree::new(depth);
+        for (count, note) in notes.iter().enumerate() {
+            tree.insert(note);
+            // Same leaves, padded to 2^depth with empty leaves
+            let mut data: Vec<&[u8]> = notes[..=count].iter().map(|n| n.as_slice()).collect();
+            data.resize(1 << depth, b"");
+            let full = MerkleTree::with_policy(data, OddNodePolicy::Promote);
+            assert_eq!(tree.root(), full.root(), "after {} inserts", count + 1);
+        }
+    }
+
+    #[test]
+    fn test_witnesses_verify() {
+        let mut tree = IncrementalMerkleTree::new(5);
+        let notes: Vec<[u8; 32]> = (0..13).map(|i| commitment(i, i)).collect();
+        for note in &notes {
+            tree.insert_tracked(note);
+        }
+        for (idx, note) in notes.iter().enumerate() {
+            let witness = tree.witness(idx).unwrap();
+            assert_eq!(witness.len(), 5);
+            assert!(verify_proof(note, &witness, tree.root()));
+            assert!(!verify_proof(&commitment(99, 99), &witness, tree.root()));
+        }
+        assert_eq!(tree.witness(13), Err(WitnessError::OutOfRange { leaf_index: 13, len: 13 }));
+    }
+
+    #[test]
+    fn test_old_witness_fails_on_new_root() {
+        let mut tree = IncrementalMerkleTree::new(3);
+        tree.insert_tracked(b"first");
+        let old_witness = tree.witness(0).unwrap();
+        tree.insert(b"second");
+        assert!(!verify_proof(b"first", &old_witness, tree.root()));
+        assert!(verify_proof(b"first", &tree.witness(0).unwrap(), tree.root()));
+    }
+
+    #[test]
+    fn test_stale_witness_within_history() {
+        let mut tree = IncrementalMerkleTree::<Sha256Hasher>::build_with_history(4, HashDomain::Plain, 3);
+        let empty_root = tree.root();
+        tree.insert_tracked(b"first");
+        let stale = tree.witness(0).unwrap();
+        tree.insert(b"second");
+        tree.insert(b"third");
+        // 3 roots kept: after "first", "second" and "third"
+        assert!(!verify_proof(b"first", &stale, tree.root()));
+        assert!(tree.verify_recent(b"first", &stale));
+        assert!(!tree.verify_recent(b"forged", &stale));
+        assert!(!tree.is_known_root(&empty_root));
+
+        tree.insert(b"fourth");
+        assert!(!tree.verify_recent(b"first", &stale));
+        assert!(tree.verify_recent(b"first", &tree.witness(0).unwrap()));
+        assert_eq!(tree.root_history().latest(), Some(tree.root()));
+    }
+
+    #[test]
+    fn test_default_history_size() {
+        let mut tree = IncrementalMerkleTree::new(6);
+        assert!(tree.is_known_root(&tree.zero_hash(6).unwrap()));
+        let roots: Vec<_> = (0..40u8).map(|i| { tree.insert(&[i]); tree.root() }).collect();
+        assert_eq!(tree.root_history().len(), 30);
+        assert!(!tree.is_known_root(&roots[9]));
+        assert!(roots[10..].iter().all(|root| tree.is_known_root(root)));
+    }
+
+    #[test]
+    fn test_generic_hasher_and_domain() {
+        let mut tree = IncrementalMerkleTree::<Keccak256Hasher>::build(3, HashDomain::Rfc6962);
+        for i in 0..5u8 {
+            tree.insert_tracked(&[i]);
+        }
+        for i in 0..5u8 {
+            let witness = tree.witness(i as usize).unwrap();
+            assert!(verify_proof_with::<Keccak256Hasher>(&[i], &witness, tree.root(), HashDomain::Rfc6962));
+        }
+    }
+
+    #[test]
+    fn test_tracked_witnesses_follow_every_insert() {
+        let depth = 4;
+        let notes: Vec<[u8; 32]> = (0..16).map(|i| commitment(i, 3 * i)).collect();
+        let mut tree = IncrementalMerkleTree::new(depth);
+        for (count, note) in notes.iter().enumerate() {
+            // Track every third leaf only
+            if count % 3 == 0 { tree.insert_tracked(note); } else { tree.insert(note); }
+            let mut data: Vec<&[u8]> = notes[..=count].iter().map(|n| n.as_slice()).collect();
+            data.resize(1 << depth, b"");
+            let full = MerkleTree::with_policy(data, OddNodePolicy::Promote);
+            for idx in 0..=count {
+                match tree.witness(idx) {
+                    Ok(witness) => assert_eq!(witness, full.proof_path(idx).unwrap(), "leaf {idx} after {} inserts", count + 1),
+                    Err(e) => assert!(idx % 3 != 0 && e == WitnessError::NotTracked(idx)),
+                }
+            }
+        }
+        assert!(tree.untrack(3));
+        assert!(!tree.untrack(3));
+        assert_eq!(tree.witness(3), Err(WitnessError::NotTracked(3)));
+        assert_eq!(tree.witness(16), Err(WitnessError::OutOfRange { leaf_index: 16, len: 16 }));
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{verify_proof_detailed, HashDomain, MerkleNode, SiblingDirection, TreeConfig};
+use crate::sparse_nodes::SparseNodes;
+
+/// Leaf of an indexed Merkle tree: a value and a link to the next bigger one
+///
+/// `next_index == 0` marks the biggest value, leaf 0 (value 0) being the head of the list.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexedLeaf {
+    pub value: u64,
+    pub next_index: usize,
+    pub next_value: u64,
+}
+
+impl IndexedLeaf {
+    /// Bytes hashed into the tree: `value || next_index || next_value`, big-endian
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let mut bytes = [0u8; 24];
+        bytes[..8].copy_from_slice(&self.value.to_be_bytes());
+        bytes[8..16].copy_from_slice(&(self.next_index as u64).to_be_bytes());
+        bytes[16..].copy_from_slice(&self.next_value.to_be_bytes());
+        bytes
+    }
+
+    //True if `value` falls in the gap after this leaf
+    fn covers(&self, value: u64) -> bool {
+        self.value < value && (self.next_index == 0 || value < self.next_value)
+    }
+}
+
+/// A leaf, its position and its authentication path
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedProof<D> {
+    pub leaf: IndexedLeaf,
+    pub index: usize,
+    pub path: Vec<(D, SiblingDirection)>,
+}
+
+/// Why an insertion was refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IndexedTreeError {
+    /// The value is already a leaf (0 always is)
+    AlreadyPresent(u64),
+    /// All 2^depth leaves are used
+    Full,
+}
+
+impl fmt::Display for IndexedTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexedTreeError::AlreadyPresent(value) => write!(f, "value {value} is already in the tree"),
+            IndexedTreeError::Full => write!(f, "the tree is full"),
+        }
+    }
+}
+
+impl std::error::Error for IndexedTreeError {}
+
+/// Indexed Merkle tree of fixed depth (Aztec style)
+///
+/// Leaves are appended in insertion order but linked in value order, so a value is
+/// proven absent with the single leaf whose gap contains it (the "low leaf"), and a
+/// shallow tree replaces a sparse tree of depth 256. Empty slots hold the empty leaf
+/// (`b""`). As in `SparseMerkleTree`, only the nodes that differ from an empty subtree
+/// are stored, so memory grows with the inserted leaves, not with 2^depth.
+pub struct IndexedMerkleTree<H: MerkleHasher = Sha256Hasher> {
+    depth: usize,
+    domain: HashDomain,
+    //nodes keyed by (height, index in the level)
+    nodes: SparseNodes<usize, H::Digest>,
+    leaves: Vec<IndexedLeaf>,
+    //value -> leaf index, to find low leaves in O(log n)
+    sorted: BTreeMap<u64, usize>,
+}
+
+impl IndexedMerkleTree {
+    /// SHA-256 tree with room for 2^depth leaves, leaf 0 included
+    pub fn new(depth: usize) -> Self {
+        Self::build(depth, HashDomain::Plain)
+    }
+}
+
+impl<H: MerkleHasher> IndexedMerkleTree<H> {
+    /// Tree with any hasher and hash domain, holding only the leaf of value 0
+    pub fn build(depth: usize, domain: HashDomain) -> Self {
+        if depth >= usize::BITS as usize {panic!("The depth must be below {}!", usize::BITS)}
+        let head = IndexedLeaf::default();
+        let mut tree = IndexedMerkleTree { depth, domain, nodes: SparseNodes::new::<H>(depth, domain), leaves: vec![head], sorted: BTreeMap::from([(0, 0)]) };
+        tree.update_leaf(0, &head);
+        tree
+    }
+
+    /// Insert `value` and return its leaf index
+    ///
+    /// The low leaf is relinked to the new one, then the new leaf is appended:
+    /// two leaf updates, as a circuit would do.
+    pub fn insert(&mut self, value: u64) -> Result<usize, IndexedTreeError> {
+        if self.sorted.contains_key(&value) { return Err(IndexedTreeError::AlreadyPresent(value)); }
+        if self.len() == self.capacity() { return Err(IndexedTreeError::Full); }
+        let index = self.leaves.len();
+        let low_index = self.low_leaf_index(value);
+        let low = self.leaves[low_index];
+        let new = IndexedLeaf { value, next_index: low.next_index, next_value: low.next_value };
+        let relinked = IndexedLeaf { next_index: index, next_value: value, ..low };
+
+        self.leaves[low_index] = relinked;
+        self.update_leaf(low_index, &relinked);
+        self.leaves.push(new);
+        self.update_leaf(index, &new);
+        self.sorted.insert(value, index);
+        Ok(index)
+    }
+
+    /// Return the current root
+    pub fn root(&self) -> H::Digest {
+        self.nodes.get(self.depth, 0)
+    }
+
+    /// Return the fixed depth of the tree
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Return the number of leaves, the leaf of value 0 included
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// True if no value was inserted yet (only leaf 0 is there)
+    pub fn is_empty(&self) -> bool {
+        self.leaves.len() == 1
+    }
+
+    /// Return the maximum number of leaves (2^depth)
+    pub fn capacity(&self) -> usize {
+        1 << self.depth()
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        self.sorted.contains_key(&value)
+    }
+
+    /// Return the leaf at `index`
+    pub fn leaf(&self, index: usize) -> Option<&IndexedLeaf> {
+        self.leaves.get(index)
+    }
+
+    /// Proof that `value` is a leaf, None if it's not
+    pub fn membership_proof(&self, value: u64) -> Option<IndexedProof<H::Digest>> {
+        self.sorted.get(&value).map(|index| self.proof_of(*index))
+    }
+
+    /// Proof that `value` is not a leaf: the low leaf whose gap contains it
+    ///
+    /// None if the value is in the tree.
+    pub fn non_membership_proof(&self, value: u64) -> Option<IndexedProof<H::Digest>> {
+        if self.contains(value) { return None; }
+        Some(self.proof_of(self.low_leaf_index(value)))
+    }
+
+    //Index of the biggest value below `value` (0 is always there)
+    fn low_leaf_index(&self, value: u64) -> usize {
+        *self.sorted.range(..value).next_back().unwrap().1
+    }
+
+    fn proof_of(&self, index: usize) -> IndexedProof<H::Digest> {
+        let path = (0..self.depth)
+            .map(|height| {
+                let position = index >> height;
+                let direction = if position % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+                (self.nodes.get(height, position ^ 1), direction)
+            })
+            .collect();
+        IndexedProof { leaf: self.leaves[index], index, path }
+    }
+
+    //Rewrite the nodes from leaf `index` up to the root
+    fn update_leaf(&mut self, index: usize, leaf: &IndexedLeaf) {
+        let mut current = MerkleNode::<H>::leaf_with(&leaf.to_bytes(), self.domain);
+        self.nodes.set(0, index, current.hash);
+        for height in 0..self.depth {
+            let position = index >> height;
+            let sibling = MerkleNode { hash: self.nodes.get(height, position ^ 1) };
+            current = if position % 2 == 1 {
+                MerkleNode::parent_with(&sibling, &current, self.domain)
+            } else {
+                MerkleNode::parent_with(&current, &sibling, self.domain)
+            };
+            self.nodes.set(height + 1, position / 2, current.hash);
+        }
+    }
+}
+
+//The leaf is at `proof.index` of a full tree of the given depth, under `root`
+fn verify_leaf<H: MerkleHasher>(proof: &IndexedProof<H::Digest>, root: H::Digest, depth: usize, domain: HashDomain) -> bool {
+    if depth >= usize::BITS as usize { return false; }
+    let config = TreeConfig { domain, ..TreeConfig::default() };
+    verify_proof_detailed::<H>(&proof.leaf.to_bytes(), proof.index, 1 << depth, &proof.path, root, &config).is_ok()
+}
+
+/// Check that `value` is a leaf of the tree of root `root`
+pub fn verify_membership<H: MerkleHasher>(value: u64, proof: &IndexedProof<H::Digest>, root: H::Digest, depth: usize, domain: HashDomain) -> bool {
+    proof.leaf.value == value && verify_leaf::<H>(proof, root, depth, domain)
+}
+
+/// Check that `value` is not a leaf: the proven low leaf is below it and links past it
+pub fn verify_non_membership<H: MerkleHasher>(value: u64, proof: &IndexedProof<H::Digest>, root: H::Digest, depth: usize, domain: HashDomain) -> bool {
+    proof.leaf.covers(value) && verify_leaf::<H>(proof, root, depth, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Keccak256Hasher;
+    use crate::merkle_tree::MerkleTree;
+
+    // Values of the linked list, head included, in link order
+    fn walk(tree: &IndexedMerkleTree) -> Vec<u64> {
+        let mut values = vec![0];
+        let mut leaf = tree.leaf(0).unwrap();
+        while leaf.next_index != 0 {
+            leaf = tree.leaf(leaf.next_index).unwrap();
+            values.push(leaf.value);
+        }
+        values
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let tree = IndexedMerkleTree::new(3);
+        assert_eq!(tree.len(), 1);
+        assert!(tree.is_empty());
+        assert_eq!(tree.capacity(), 8);
+        assert!(tree.contains(0));
+        let proof = tree.non_membership_proof(42).unwrap();
+        assert_eq!(proof.index, 0);
+        assert!(verify_non_membership::<Sha256Hasher>(42, &proof, tree.root(), 3, HashDomain::Plain));
+    }
+
+    #[test]
+    fn test_insert_keeps_values_linked_in_order() {
+        let mut tree = IndexedMerkleTree::new(4);
+        for (i, value) in [30, 10, 20, 50, 40].into_iter().enumerate() {
+            assert_eq!(tree.insert(value), Ok(i + 1));
+        }
+        assert!(!tree.is_empty());
+        assert_eq!(walk(&tree), vec![0, 10, 20, 30, 40, 50]);
+        assert_eq!(tree.leaf(1), Some(&IndexedLeaf { value: 30, next_index: 5, next_value: 40 }));
+        assert_eq!(tree.leaf(4), Some(&IndexedLeaf { value: 50, next_index: 0, next_value: 0 }));
+    }
+
+    #[test]
+    fn test_root_matches_merkle_tree() {
+        let mut tree = IndexedMerkleTree::<Keccak256Hasher>::build(3, HashDomain::Rfc6962);
+        for value in [7, 3, 9] {
+            tree.insert(value).unwrap();
+        }
+        let mut data: Vec<Vec<u8>> = (0..tree.len()).map(|i| tree.leaf(i).unwrap().to_bytes().to_vec()).collect();
+        data.resize(8, Vec::new());
+        let config = TreeConfig { domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+        let expected = MerkleTree::<Keccak256Hasher>::build(data.iter().map(|d| d.as_slice()).collect(), config);
+        assert_eq!(tree.root(), expected.root());
+    }
+
+    #[test]
+    fn test_deep_tree_stores_only_inserted_paths() {
+        let mut tree = IndexedMerkleTree::new(32);
+        for value in [1 << 40, 3, u64::MAX] {
+            tree.insert(value).unwrap();
+        }
+        // Each leaf path is 33 nodes long, shared near the root
+        assert!(tree.nodes.len() <= 4 * 33);
+        let proof = tree.membership_proof(3).unwrap();
+        assert_eq!(proof.path.len(), 32);
+        assert!(verify_membership::<Sha256Hasher>(3, &proof, tree.root(), 32, HashDomain::Plain));
+        let proof = tree.non_membership_proof(4).unwrap();
+        assert!(verify_non_membership::<Sha256Hasher>(4, &proof, tree.root(), 32, HashDomain::Plain));
+    }
+
+    #[test]
+    fn test_membership_and_non_membership_proofs() {
+        let mut tree = IndexedMerkleTree::new(5);
+        for value in [100, 5, 60, 1000] {
+            tree.insert(value).unwrap();
+        }
+        let root = tree.root();
+        for value in [0, 5, 60, 100, 1000] {
+            let proof = tree.membership_proof(value).unwrap();
+            assert!(verify_membership::<Sha256Hasher>(value, &proof, root, 5, HashDomain::Plain));
+            assert!(!verify_membership::<Sha256Hasher>(value + 1, &proof, root, 5, HashDomain::Plain));
+            assert!(tree.non_membership_proof(value).is_none());
+        }
+        for (value, low) in [(1, 0), (6, 5), (99, 60), (101, 100), (5000, 1000)] {
+            let proof = tree.non_membership_proof(value).unwrap();
+            assert_eq!(proof.leaf.value, low);
+            assert!(verify_non_membership::<Sha256Hasher>(value, &proof, root, 5, HashDomain::Plain));
+            // The gap ends where the next value starts
+            if proof.leaf.next_index != 0 {
+                assert!(!verify_non_membership::<Sha256Hasher>(proof.leaf.next_value, &proof, root, 5, HashDomain::Plain));
+            }
+        }
+        assert!(tree.membership_proof(7).is_none());
+    }
+
+    #[test]
+    fn test_forged_proofs_rejected() {
+        let mut tree = IndexedMerkleTree::new(3);
+        tree.insert(10).unwrap();
+        tree.insert(20).unwrap();
+        let root = tree.root();
+        let proof = tree.non_membership_proof(15).unwrap();
+
+        // Claim the gap is wider to hide 20
+        let mut widened = proof.clone();
+        widened.leaf.next_value = 30;
+        assert!(!verify_non_membership::<Sha256Hasher>(20, &widened, root, 3, HashDomain::Plain));
+        // Same leaf at another position
+        let mut moved = proof.clone();
+        moved.index ^= 1;
+        assert!(!verify_non_membership::<Sha256Hasher>(15, &moved, root, 3, HashDomain::Plain));
+        // The proof of a stale root doesn't verify once the gap is filled
+        tree.insert(15).unwrap();
+        assert!(!verify_non_membership::<Sha256Hasher>(15, &proof, tree.root(), 3, HashDomain::Plain));
+    }
+
+    #[test]
+    fn test_insert_errors() {
+        let mut tree = IndexedMerkleTree::new(1);
+        assert_eq!(tree.insert(0), Err(IndexedTreeError::AlreadyPresent(0)));
+        assert_eq!(tree.insert(8), Ok(1));
+        assert_eq!(tree.insert(8), Err(IndexedTreeError::AlreadyPresent(8)));
+        assert_eq!(tree.insert(9), Err(IndexedTreeError::Full));
+    }
+}
@@ -1 +1,17 @@
+pub mod bitcoin_merkle;
+pub mod incremental_merkle_tree;
+pub mod indexed_merkle_tree;
+pub mod merkle_diff;
+pub mod merkle_hasher;
+pub mod merkle_mountain_range;
+pub mod merkle_patricia_trie;
+pub mod merkle_proof;
+pub mod merkle_render;
+pub mod merkle_store;
+pub mod merkle_stream;
+pub mod merkle_sum_tree;
 pub mod merkle_tree;
+pub mod rlp;
+pub mod root_history;
+pub mod sparse_merkle_tree;
+mod sparse_nodes;
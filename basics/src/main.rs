@@ -1,5 +1,361 @@
-use basics::merkle_tree::MerkleTree;
+//! `merkle`: check file integrity chunk by chunk
+//!
+//! ```text
+//! merkle build <file> [--chunk-size <bytes>] [--manifest <path>] [--json]
+//! merkle prove <manifest> <index> [--out <path>] [--chunk-out <path>] [--json]
+//! merkle verify <chunk> <proof> <root> [--json]
+//! ```
+//!
+//! Exit codes: 0 success (proof valid), 1 proof invalid, 2 bad usage, 3 I/O or format error.
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() {
-    println!("Library structure ready!");
+use serde_json::{json, Value};
+
+use basics::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use basics::merkle_proof::{MerkleProof, ProofFormatError};
+use basics::merkle_tree::{verify_proof_detailed, Hash, MerkleNode, MerkleTree, TreeConfig};
+
+/// Chunk size used by `build` without `--chunk-size`
+const DEFAULT_CHUNK_SIZE: usize = 1024;
+/// Version of the manifest written by `build`
+const MANIFEST_VERSION: u64 = 1;
+
+const USAGE: &str = "usage:
+  merkle build <file> [--chunk-size <bytes>] [--manifest <path>] [--json]
+  merkle prove <manifest> <index> [--out <path>] [--chunk-out <path>] [--json]
+  merkle verify <chunk> <proof> <root> [--json]";
+
+//Why a command failed, mapped to an exit code
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Invalid(String),
+    Failed(String),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Invalid(_) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Failed(_) => 3,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            CliError::Usage(msg) | CliError::Invalid(msg) | CliError::Failed(msg) => msg,
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Failed(e.to_string())
+    }
+}
+
+//Command-line arguments split into positionals and `--name value` options
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+    json: bool,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, CliError> {
+        let mut parsed = Args { positional: Vec::new(), options: Vec::new(), json: false };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some("json") => parsed.json = true,
+                Some(name) => {
+                    let value = args.next().ok_or_else(|| CliError::Usage(format!("missing value for --{name}")))?;
+                    parsed.options.push((name.to_string(), value.clone()));
+                }
+                None => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    //Exactly `N` positionals, and no option outside `allowed`
+    fn expect<const N: usize>(&self, allowed: &[&str]) -> Result<[&str; N], CliError> {
+        if let Some((name, _)) = self.options.iter().find(|(name, _)| !allowed.contains(&name.as_str())) {
+            return Err(CliError::Usage(format!("unknown option --{name}")));
+        }
+        let positional: Vec<&str> = self.positional.iter().map(String::as_str).collect();
+        positional.try_into().map_err(|_| CliError::Usage(format!("expected {N} arguments")))
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.iter().rev().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|arg| arg == "--json");
+    match run(&args) {
+        Ok(output) => {
+            println!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            if json {
+                println!("{}", json!({ "ok": false, "error": e.message() }));
+            } else {
+                eprintln!("error: {}", e.message());
+                if let CliError::Usage(_) = e { eprintln!("{USAGE}") }
+            }
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+//Run a command and return what to print
+fn run(args: &[String]) -> Result<String, CliError> {
+    let (command, rest) = args.split_first().ok_or_else(|| CliError::Usage("missing command".to_string()))?;
+    let args = Args::parse(rest)?;
+    match command.as_str() {
+        "build" => build(&args),
+        "prove" => prove(&args),
+        "verify" => verify(&args),
+        other => Err(CliError::Usage(format!("unknown command `{other}`"))),
+    }
+}
+
+//merkle build <file>: hash every chunk, write the manifest, print the root
+fn build(args: &Args) -> Result<String, CliError> {
+    let [file] = args.expect(&["chunk-size", "manifest"])?;
+    let chunk_size = match args.option("chunk-size") {
+        None => DEFAULT_CHUNK_SIZE,
+        Some(size) => size.parse().ok().filter(|size| *size > 0)
+            .ok_or_else(|| CliError::Usage(format!("invalid chunk size `{size}`")))?,
+    };
+    let manifest_path = args.option("manifest").map(PathBuf::from).unwrap_or_else(|| default_manifest(file));
+
+    let chunks = chunk_hashes(File::open(file)?, chunk_size)?;
+    let file_size = fs::metadata(file)?.len();
+    let tree = MerkleTree::<Sha256Hasher>::from_leaf_hashes(chunks, TreeConfig::default())
+        .map_err(|e| CliError::Failed(format!("{file}: {e}")))?;
+    let manifest = json!({
+        "version": MANIFEST_VERSION,
+        "hash": Sha256Hasher::NAME,
+        "file": fs::canonicalize(file)?,
+        "file_size": file_size,
+        "chunk_size": chunk_size,
+        "root": hex::encode(tree.root()),
+        "chunks": tree.leaves().iter().map(hex::encode).collect::<Vec<_>>(),
+    });
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest).unwrap())?;
+
+    let root = hex::encode(tree.root());
+    Ok(if args.json {
+        json!({ "ok": true, "root": root, "chunks": tree.num_leaves(), "manifest": manifest_path }).to_string()
+    } else {
+        root
+    })
+}
+
+//merkle prove <manifest> <index>: proof of chunk `index`, as MerkleProof JSON
+fn prove(args: &Args) -> Result<String, CliError> {
+    let [manifest_path, index] = args.expect(&["out", "chunk-out"])?;
+    let index: usize = index.parse().map_err(|_| CliError::Usage(format!("invalid chunk index `{index}`")))?;
+    let manifest = Manifest::read(Path::new(manifest_path))?;
+    let tree = MerkleTree::<Sha256Hasher>::from_leaf_hashes(manifest.chunks.clone(), TreeConfig::default())
+        .map_err(|e| CliError::Failed(format!("{manifest_path}: {e}")))?;
+    if tree.root() != manifest.root {
+        return Err(CliError::Failed(format!("{manifest_path}: chunks do not match the root")));
+    }
+    let proof = MerkleProof::from_tree(&tree, index)
+        .ok_or_else(|| CliError::Usage(format!("chunk {index} out of range for {} chunks", tree.num_leaves())))?;
+
+    let proof_json = proof.to_json();
+    if let Some(out) = args.option("out") { fs::write(out, &proof_json)? }
+    if let Some(chunk_out) = args.option("chunk-out") {
+        let chunk = manifest.read_chunk(index)?;
+        if MerkleNode::leaf(&chunk).hash != manifest.chunks[index] {
+            return Err(CliError::Failed(format!("{}: chunk {index} changed since the manifest was built", manifest.file)));
+        }
+        fs::write(chunk_out, chunk)?;
+    }
+
+    Ok(match (args.json, args.option("out")) {
+        (true, _) => json!({ "ok": true, "index": index, "root": hex::encode(tree.root()), "proof": serde_json::from_str::<Value>(&proof_json).unwrap() }).to_string(),
+        (false, Some(out)) => format!("proof of chunk {index} written to {out}"),
+        (false, None) => proof_json,
+    })
+}
+
+//merkle verify <chunk> <proof> <root>: check the chunk bytes and their position
+fn verify(args: &Args) -> Result<String, CliError> {
+    let [chunk_path, proof_path, root] = args.expect(&[])?;
+    let root: Hash = hex::decode(root).ok().and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| CliError::Usage(format!("invalid root `{root}`")))?;
+    let chunk = fs::read(chunk_path)?;
+    let proof = MerkleProof::<Sha256Hasher>::from_json(&fs::read_to_string(proof_path)?).map_err(|e| match e {
+        //well-formed, but not the path of the position it claims
+        ProofFormatError::InvalidShape(_) => CliError::Invalid(format!("{proof_path}: {e}")),
+        e => CliError::Failed(format!("{proof_path}: {e}")),
+    })?;
+
+    //the position is proven too: the path must be the one of `leaf_index` among `tree_size` chunks
+    let (index, chunks) = (proof.leaf_index as usize, proof.tree_size as usize);
+    if let Err(e) = verify_proof_detailed::<Sha256Hasher>(&chunk, index, chunks, &proof.path, root, &TreeConfig::default()) {
+        return Err(CliError::Invalid(format!("chunk {index} of {chunks} does not match the root: {e}")));
+    }
+    Ok(if args.json {
+        json!({ "ok": true, "valid": true, "index": index, "chunks": chunks }).to_string()
+    } else {
+        format!("chunk {index} of {chunks} is valid")
+    })
+}
+
+//Leaf hash of every `chunk_size`-byte chunk of `reader`, the last one may be shorter
+fn chunk_hashes(reader: impl Read, chunk_size: usize) -> io::Result<Vec<Hash>> {
+    let mut reader = BufReader::new(reader);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut hashes = Vec::new();
+    loop {
+        chunk.clear();
+        (&mut reader).take(chunk_size as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() { return Ok(hashes); }
+        hashes.push(MerkleNode::leaf(&chunk).hash);
+        if chunk.len() < chunk_size { return Ok(hashes); }
+    }
+}
+
+//`data.bin` -> `data.bin.merkle.json`
+fn default_manifest(file: &str) -> PathBuf {
+    PathBuf::from(format!("{file}.merkle.json"))
+}
+
+//What `build` wrote, parsed back
+struct Manifest {
+    file: String,
+    chunk_size: usize,
+    root: Hash,
+    chunks: Vec<Hash>,
+}
+
+impl Manifest {
+    fn read(path: &Path) -> Result<Self, CliError> {
+        let invalid = |what: &str| CliError::Failed(format!("{}: invalid manifest ({what})", path.display()));
+        let value: Value = serde_json::from_str(&fs::read_to_string(path)?).map_err(|_| invalid("not JSON"))?;
+        if value["version"].as_u64() != Some(MANIFEST_VERSION) { return Err(invalid("version")); }
+        if value["hash"].as_str() != Some(Sha256Hasher::NAME) { return Err(invalid("hash")); }
+        let hash = |value: &Value| value.as_str().and_then(|s| hex::decode(s).ok()).and_then(|bytes| bytes.try_into().ok());
+        Ok(Manifest {
+            file: value["file"].as_str().ok_or_else(|| invalid("file"))?.to_string(),
+            chunk_size: value["chunk_size"].as_u64().filter(|size| *size > 0).ok_or_else(|| invalid("chunk_size"))? as usize,
+            root: hash(&value["root"]).ok_or_else(|| invalid("root"))?,
+            chunks: value["chunks"].as_array().ok_or_else(|| invalid("chunks"))?
+                .iter()
+                .map(hash)
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid("chunks"))?,
+        })
+    }
+
+    //Bytes of chunk `index`, read from the original file
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>> {
+        let mut file = File::open(&self.file)?;
+        file.seek(SeekFrom::Start((index * self.chunk_size) as u64))?;
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        file.take(self.chunk_size as u64).read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // Unique per test and per process
+    fn temp(name: &str) -> String {
+        std::env::temp_dir().join(format!("merkle_cli_{}_{name}", std::process::id())).display().to_string()
+    }
+
+    #[test]
+    fn test_build_prove_verify() {
+        let (file, manifest, proof, chunk) = (temp("data"), temp("manifest.json"), temp("proof.json"), temp("chunk"));
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&file, &data).unwrap();
+
+        let root = run(&args(&["build", &file, "--chunk-size", "64", "--manifest", &manifest])).unwrap();
+        let chunks: Vec<&[u8]> = data.chunks(64).collect();
+        assert_eq!(root, hex::encode(MerkleTree::new(chunks.clone()).root()));
+
+        run(&args(&["prove", &manifest, "15", "--out", &proof, "--chunk-out", &chunk])).unwrap();
+        assert_eq!(fs::read(&chunk).unwrap(), chunks[15]);
+        let output = run(&args(&["verify", &chunk, &proof, &root, "--json"])).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&output).unwrap()["valid"], true);
+
+        assert_eq!(serde_json::from_str::<Value>(&output).unwrap()["index"], 15);
+
+        // Another chunk with the same proof
+        fs::write(&chunk, chunks[14]).unwrap();
+        let err = run(&args(&["verify", &chunk, &proof, &root])).unwrap_err();
+        assert_eq!(err.exit_code(), 1);
+
+        // The right chunk and path, claimed at another position
+        fs::write(&chunk, chunks[15]).unwrap();
+        let mut relabelled: Value = serde_json::from_str(&fs::read_to_string(&proof).unwrap()).unwrap();
+        relabelled["leaf_index"] = json!(14);
+        fs::write(&proof, relabelled.to_string()).unwrap();
+        assert_eq!(run(&args(&["verify", &chunk, &proof, &root])).unwrap_err().exit_code(), 1);
+
+        for path in [file, manifest, proof, chunk] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_json_output() {
+        let (file, manifest) = (temp("json_data"), temp("json_manifest.json"));
+        fs::write(&file, b"hello world").unwrap();
+        let output = run(&args(&["build", &file, "--chunk-size", "4", "--manifest", &manifest, "--json"])).unwrap();
+        let value: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["ok"], true);
+        assert_eq!(value["chunks"], 3);
+
+        let output = run(&args(&["prove", &manifest, "2", "--json"])).unwrap();
+        let value: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["proof"]["leaf_index"], 2);
+        fs::remove_file(file).unwrap();
+        fs::remove_file(manifest).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(run(&args(&[])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["frobnicate"])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["build"])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["build", "x", "--chunk-size", "0"])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["build", "x", "--colour", "red"])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["verify", "a", "b", "not-hex"])).unwrap_err().exit_code(), 2);
+        assert_eq!(run(&args(&["build", &temp("missing")])).unwrap_err().exit_code(), 3);
+
+        let empty = temp("empty");
+        fs::write(&empty, b"").unwrap();
+        assert_eq!(run(&args(&["build", &empty])).unwrap_err().exit_code(), 3);
+        fs::remove_file(empty).unwrap();
+    }
+
+    #[test]
+    fn test_chunk_hashes() {
+        let data = vec![7u8; 10];
+        let hashes = chunk_hashes(data.as_slice(), 4).unwrap();
+        assert_eq!(hashes, vec![MerkleNode::leaf(&[7; 4]).hash, MerkleNode::leaf(&[7; 4]).hash, MerkleNode::leaf(&[7; 2]).hash]);
+        assert_eq!(chunk_hashes(&data[..8], 4).unwrap().len(), 2);
+        assert!(chunk_hashes(io::empty(), 4).unwrap().is_empty());
+    }
 }
@@ -0,0 +1,306 @@
+use std::io::{self, Read, Write};
+
+use crate::merkle_hasher::MerkleHasher;
+use crate::merkle_proof::{domain_id, policy_id};
+use crate::merkle_tree::MerkleTree;
+
+/// Indices of the leaves that differ between two trees of the same shape
+///
+/// The trees are walked top-down and only the subtrees whose hashes differ are
+/// explored, so few differences cost O(differences * depth) comparisons.
+/// Returns None if the trees don't have the same shape (leaf count, odd-node policy and arity)
+/// or hash domain, since then every node differs.
+pub fn diff_leaves<H: MerkleHasher>(a: &MerkleTree<H>, b: &MerkleTree<H>) -> Option<Vec<usize>> {
+    if a.num_leaves() != b.num_leaves() || a.policy() != b.policy() || a.arity() != b.arity() || a.domain() != b.domain() { return None; }
+    let mut suspects = vec![0];
+    for level in (0..=a.depth()).rev() {
+        let (layer_a, layer_b) = (a.layer(level).unwrap(), b.layer(level).unwrap());
+        let differing: Vec<usize> = suspects.into_iter().filter(|idx| layer_a[*idx] != layer_b[*idx]).collect();
+        if level == 0 { return Some(leaves_only(differing, a.num_leaves())); }
+        suspects = children(&differing, a.arity(), a.layer(level - 1).unwrap().len());
+    }
+    unreachable!("level 0 is always reached")
+}
+
+/// Side of the `reconcile` protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncRole {
+    /// Sends its hashes, one level at a time
+    Initiator,
+    /// Compares them with its own and answers which ones differ
+    Responder,
+}
+
+/// Find the leaves that differ between our tree and a peer's, over a byte stream
+///
+/// Each peer calls this with its own tree and the opposite role. After a shape
+/// check (hasher, hash domain, odd-node policy, arity and leaf count), the initiator sends the hashes of the suspect nodes of one level, root
+/// first, and the responder answers with a bitmap of the ones that differ (one bit
+/// per hash, LSB first). The children of differing nodes are the suspects of the
+/// next level. Both peers return the same differing leaf indices.
+pub fn reconcile<H: MerkleHasher, S: Read + Write>(tree: &MerkleTree<H>, stream: &mut S, role: SyncRole) -> io::Result<Vec<usize>> {
+    check_shape(tree, stream, role)?;
+    let mut suspects = vec![0];
+    for level in (0..=tree.depth()).rev() {
+        let layer = tree.layer(level).unwrap();
+        let bitmap = match role {
+            SyncRole::Initiator => {
+                for idx in &suspects {
+                    stream.write_all(layer[*idx].as_ref())?;
+                }
+                stream.flush()?;
+                let mut bitmap = vec![0u8; suspects.len().div_ceil(8)];
+                stream.read_exact(&mut bitmap)?;
+                bitmap
+            }
+            SyncRole::Responder => {
+                let mut theirs = vec![0u8; H::DIGEST_SIZE];
+                let mut bitmap = vec![0u8; suspects.len().div_ceil(8)];
+                for (i, idx) in suspects.iter().enumerate() {
+                    stream.read_exact(&mut theirs)?;
+                    if layer[*idx].as_ref() != theirs.as_slice() { bitmap[i / 8] |= 1 << (i % 8) }
+                }
+                stream.write_all(&bitmap)?;
+                stream.flush()?;
+                bitmap
+            }
+        };
+        let differing: Vec<usize> = suspects.iter()
+            .enumerate()
+            .filter(|(i, _)| bitmap[i / 8] >> (i % 8) & 1 == 1)
+            .map(|(_, idx)| *idx)
+            .collect();
+        if level == 0 { return Ok(leaves_only(differing, tree.num_leaves())); }
+        suspects = children(&differing, tree.arity(), tree.layer(level - 1).unwrap().len());
+    }
+    unreachable!("level 0 is always reached")
+}
+
+//Both peers must have trees of the same hasher and shape, otherwise node indices and hashes don't match
+fn check_shape<H: MerkleHasher, S: Read + Write>(tree: &MerkleTree<H>, stream: &mut S, role: SyncRole) -> io::Result<()> {
+    //hash id, domain, policy, pad length, arity, leaf count, width of layer 0, then the pad leaf
+    let (policy, pad) = policy_id(tree.policy());
+    let mut shape = vec![H::ID, domain_id(tree.domain()), policy];
+    shape.extend_from_slice(&(pad.len() as u64).to_le_bytes());
+    shape.extend_from_slice(&(tree.arity() as u64).to_le_bytes());
+    shape.extend_from_slice(&(tree.num_leaves() as u64).to_le_bytes());
+    shape.extend_from_slice(&(tree.layer(0).unwrap().len() as u64).to_le_bytes());
+    let fixed_len = shape.len();
+    shape.extend_from_slice(pad);
+    let matches = match role {
+        SyncRole::Initiator => {
+            stream.write_all(&shape)?;
+            stream.flush()?;
+            let mut answer = [0u8];
+            stream.read_exact(&mut answer)?;
+            answer[0] == 1
+        }
+        SyncRole::Responder => {
+            //the pad is only read once both pad lengths agree
+            let mut theirs = vec![0u8; fixed_len];
+            stream.read_exact(&mut theirs)?;
+            if theirs == shape[..fixed_len] {
+                theirs.resize(shape.len(), 0);
+                stream.read_exact(&mut theirs[fixed_len..])?;
+            }
+            let matches = theirs == shape;
+            stream.write_all(&[matches as u8])?;
+            stream.flush()?;
+            matches
+        }
+    };
+    if !matches { return Err(io::Error::new(io::ErrorKind::InvalidData, "the trees do not have the same shape")); }
+    Ok(())
+}
+
+//Children of `nodes` that exist in the level below (the last node of an odd layer may have fewer)
+fn children(nodes: &[usize], arity: usize, width_below: usize) -> Vec<usize> {
+    nodes.iter()
+        .flat_map(|idx| arity * idx..(arity * idx + arity).min(width_below))
+        .collect()
+}
+
+//Padding leaves are identical on both sides, but never report them
+fn leaves_only(mut indices: Vec<usize>, num_leaves: usize) -> Vec<usize> {
+    indices.retain(|idx| *idx < num_leaves);
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Sha256Hasher;
+    use crate::merkle_tree::{HashDomain, OddNodePolicy, TreeConfig};
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+
+    fn items(size: usize) -> Vec<Vec<u8>> {
+        (0..size).map(|i| format!("item_{}", i).into_bytes()).collect()
+    }
+
+    fn tree_with(data: &[Vec<u8>], config: TreeConfig) -> MerkleTree {
+        MerkleTree::with_config(data.iter().map(|d| d.as_slice()).collect(), config)
+    }
+
+    // One end of an in-memory full-duplex pipe, reads hit EOF once the other end is dropped
+    struct Duplex {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        pending: VecDeque<u8>,
+    }
+
+    fn duplex() -> (Duplex, Duplex) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (Duplex { tx: a_tx, rx: a_rx, pending: VecDeque::new() }, Duplex { tx: b_tx, rx: b_rx, pending: VecDeque::new() })
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.rx.recv() {
+                    Ok(chunk) => self.pending.extend(chunk),
+                    Err(_) => return Ok(0),
+                }
+            }
+            self.pending.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Run both sides of `reconcile` over an in-memory stream
+    fn reconcile_over_duplex(ours: MerkleTree, theirs: MerkleTree) -> (io::Result<Vec<usize>>, io::Result<Vec<usize>>) {
+        let (mut stream, mut other_end) = duplex();
+        let responder = thread::spawn(move || reconcile(&theirs, &mut other_end, SyncRole::Responder));
+        let initiator = reconcile(&ours, &mut stream, SyncRole::Initiator);
+        drop(stream);
+        (initiator, responder.join().unwrap())
+    }
+
+    #[test]
+    fn test_identical_trees() {
+        let data = items(10);
+        let tree = tree_with(&data, TreeConfig::default());
+        assert_eq!(diff_leaves(&tree, &tree_with(&data, TreeConfig::default())), Some(vec![]));
+    }
+
+    #[test]
+    fn test_diff_finds_changed_leaves() {
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = TreeConfig { odd_policy, ..TreeConfig::default() };
+            let data = items(37);
+            let mut changed = data.clone();
+            for idx in [0, 5, 6, 36] {
+                changed[idx] = b"changed".to_vec();
+            }
+            let diff = diff_leaves(&tree_with(&data, config.clone()), &tree_with(&changed, config.clone()));
+            assert_eq!(diff, Some(vec![0, 5, 6, 36]), "{config:?}");
+            let quad = TreeConfig { arity: 4, ..config };
+            assert_eq!(diff_leaves(&tree_with(&data, quad.clone()), &tree_with(&changed, quad)), Some(vec![0, 5, 6, 36]));
+        }
+    }
+
+    #[test]
+    fn test_diff_needs_same_shape() {
+        let config = TreeConfig::default();
+        assert_eq!(diff_leaves(&tree_with(&items(4), config.clone()), &tree_with(&items(5), config)), None);
+        let dup = TreeConfig { odd_policy: OddNodePolicy::DuplicateLast, ..TreeConfig::default() };
+        assert_eq!(diff_leaves(&tree_with(&items(5), TreeConfig::default()), &tree_with(&items(5), dup)), None);
+        let quad = TreeConfig { arity: 4, ..TreeConfig::default() };
+        assert_eq!(diff_leaves(&tree_with(&items(5), TreeConfig::default()), &tree_with(&items(5), quad)), None);
+        let rfc = TreeConfig { domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+        assert_eq!(diff_leaves(&tree_with(&items(5), TreeConfig::default()), &tree_with(&items(5), rfc)), None);
+    }
+
+    #[test]
+    fn test_reconcile_over_a_stream() {
+        let data = items(1000);
+        let mut changed = data.clone();
+        for idx in [3, 512, 999] {
+            changed[idx] = b"changed".to_vec();
+        }
+        let (ours, theirs) = (tree_with(&data, TreeConfig::default()), tree_with(&changed, TreeConfig::default()));
+        let expected = diff_leaves(&ours, &theirs).unwrap();
+        let (initiator, responder) = reconcile_over_duplex(ours, theirs);
+        assert_eq!(initiator.unwrap(), expected);
+        assert_eq!(responder.unwrap(), vec![3, 512, 999]);
+    }
+
+    #[test]
+    fn test_reconcile_identical_and_mismatched() {
+        let data = items(16);
+        let (initiator, responder) = reconcile_over_duplex(tree_with(&data, TreeConfig::default()), tree_with(&data, TreeConfig::default()));
+        assert_eq!(initiator.unwrap(), Vec::<usize>::new());
+        assert_eq!(responder.unwrap(), Vec::<usize>::new());
+
+        let (initiator, responder) = reconcile_over_duplex(tree_with(&data, TreeConfig::default()), tree_with(&items(17), TreeConfig::default()));
+        assert_eq!(initiator.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(responder.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_reconcile_needs_same_policy_and_domain() {
+        // 16 leaves: the same widths under every policy
+        let data = items(16);
+        let pad = |empty: &[u8]| TreeConfig { odd_policy: OddNodePolicy::PadWith(empty.to_vec()), ..TreeConfig::default() };
+        let rfc = TreeConfig { domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+        for (ours, theirs) in [(TreeConfig::default(), pad(b"")), (pad(b"a"), pad(b"b")), (pad(b""), pad(b"pad")), (TreeConfig::default(), rfc)] {
+            let (initiator, responder) = reconcile_over_duplex(tree_with(&data, ours.clone()), tree_with(&data, theirs.clone()));
+            assert_eq!(initiator.unwrap_err().kind(), io::ErrorKind::InvalidData, "{ours:?} / {theirs:?}");
+            assert_eq!(responder.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let (initiator, responder) = reconcile_over_duplex(tree_with(&data, pad(b"pad")), tree_with(&data, pad(b"pad")));
+        assert_eq!((initiator.unwrap(), responder.unwrap()), (vec![], vec![]));
+    }
+
+    // Counts the bytes written through it
+    struct Counting<S> {
+        inner: S,
+        written: usize,
+    }
+
+    impl<S: Read> Read for Counting<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for Counting<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            self.written += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_reconcile_sends_few_hashes() {
+        let data = items(4096);
+        let mut changed = data.clone();
+        changed[1234] = b"changed".to_vec();
+        let theirs = tree_with(&changed, TreeConfig::default());
+        let (stream, mut other_end) = duplex();
+        let responder = thread::spawn(move || reconcile(&theirs, &mut other_end, SyncRole::Responder).unwrap());
+        let mut stream = Counting { inner: stream, written: 0 };
+        let diff = reconcile::<Sha256Hasher, _>(&tree_with(&data, TreeConfig::default()), &mut stream, SyncRole::Initiator).unwrap();
+        assert_eq!(diff, vec![1234]);
+        assert_eq!(responder.join().unwrap(), vec![1234]);
+        // Shape, the root, then two hashes per level
+        assert_eq!(stream.written, 35 + 32 + 12 * 2 * 32);
+    }
+}
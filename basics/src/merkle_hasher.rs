@@ -0,0 +1,128 @@
+use std::fmt::Debug;
+use std::hash::Hash as StdHash;
+
+use blake2::Blake2s256;
+use sha2::{Digest, Sha256, Sha512_256};
+use sha3::Keccak256;
+
+/// A hash function a Merkle tree can be built with
+///
+/// Hashers are zero-sized marker types: the tree only calls `H::hash`, so any
+/// function with a fixed-size output fits (byte-oriented or field-based).
+pub trait MerkleHasher: Copy + Debug + Default + Send + Sync + 'static {
+    /// Output of the hash function, e.g. `[u8; 32]` for SHA-256
+    type Digest: Copy + Eq + StdHash + Debug + AsRef<[u8]> + for<'a> TryFrom<&'a [u8]> + Send + Sync;
+
+    /// Length of a digest in bytes, used to split serialized hashes
+    const DIGEST_SIZE: usize;
+
+    /// Identifier written in serialized proofs, unique per hasher
+    const ID: u8;
+
+    /// Human-readable name, used in JSON proofs
+    const NAME: &'static str;
+
+    /// Hash the concatenation of `parts`
+    fn hash(parts: &[&[u8]]) -> Self::Digest;
+}
+
+//every RustCrypto hash with a 32-byte output plugs in the same way
+macro_rules! digest_hasher {
+    ($(#[$doc:meta])* $name:ident, $inner:ty, $id:literal, $label:literal) => {
+        $(#[$doc])*
+        #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+        pub struct $name;
+
+        impl MerkleHasher for $name {
+            type Digest = [u8; 32];
+            const DIGEST_SIZE: usize = 32;
+            const ID: u8 = $id;
+            const NAME: &'static str = $label;
+
+            fn hash(parts: &[&[u8]]) -> Self::Digest {
+                let mut hasher = <$inner>::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.finalize().into()
+            }
+        }
+    };
+}
+
+digest_hasher!(
+    /// SHA-256, the default hasher of the crate
+    Sha256Hasher, Sha256, 1, "sha256"
+);
+digest_hasher!(
+    /// SHA-512 truncated to 256 bits (faster than SHA-256 on 64-bit CPUs)
+    Sha512_256Hasher, Sha512_256, 2, "sha512_256"
+);
+digest_hasher!(
+    /// Keccak-256 as used by Ethereum (not the NIST SHA3-256 padding)
+    Keccak256Hasher, Keccak256, 3, "keccak256"
+);
+digest_hasher!(
+    /// BLAKE2s with a 256-bit output
+    Blake2sHasher, Blake2s256, 4, "blake2s"
+);
+
+/// Double SHA-256, `SHA256(SHA256(x))`, as used by Bitcoin for txids and block hashes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Sha256dHasher;
+
+impl MerkleHasher for Sha256dHasher {
+    type Digest = [u8; 32];
+    const DIGEST_SIZE: usize = 32;
+    const ID: u8 = 5;
+    const NAME: &'static str = "sha256d";
+
+    fn hash(parts: &[&[u8]]) -> Self::Digest {
+        Sha256::digest(Sha256Hasher::hash(parts)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_of<H: MerkleHasher>(parts: &[&[u8]]) -> String {
+        hex::encode(H::hash(parts))
+    }
+
+    #[test]
+    fn test_known_vectors() {
+        assert_eq!(
+            hex_of::<Sha256Hasher>(&[b"abc"]),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex_of::<Sha512_256Hasher>(&[b"abc"]),
+            "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"
+        );
+        assert_eq!(
+            hex_of::<Keccak256Hasher>(&[b""]),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        assert_eq!(
+            hex_of::<Blake2sHasher>(&[b"abc"]),
+            "508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982"
+        );
+        assert_eq!(
+            hex_of::<Sha256dHasher>(&[b"hello"]),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+    }
+
+    #[test]
+    fn test_digest_size() {
+        assert_eq!(Sha256Hasher::hash(&[b"x"]).len(), Sha256Hasher::DIGEST_SIZE);
+        assert_eq!(Blake2sHasher::hash(&[b"x"]).len(), Blake2sHasher::DIGEST_SIZE);
+    }
+
+    #[test]
+    fn test_parts_are_concatenated() {
+        assert_eq!(Sha256Hasher::hash(&[b"a", b"bc"]), Sha256Hasher::hash(&[b"abc"]));
+        assert_eq!(Keccak256Hasher::hash(&[b"ab", b"", b"c"]), Keccak256Hasher::hash(&[b"abc"]));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{HashDomain, MerkleNode, SiblingDirection};
+
+/// Merkle Mountain Range: an append-only list of perfect binary trees
+///
+/// `n` leaves form one mountain per set bit of `n`, highest first. Their roots
+/// (the peaks) are bagged right to left into one root, which makes the root
+/// equal to the one of `MerkleTree::new` (RFC 6962 shape) over the same leaves.
+pub struct MerkleMountainRange<H: MerkleHasher = Sha256Hasher> {
+    domain: HashDomain,
+    //layers[h] = every completed node of height h, left to right
+    layers: Vec<Vec<H::Digest>>,
+}
+
+impl MerkleMountainRange {
+    /// Empty SHA-256 range
+    pub fn new() -> Self {
+        Self::build(HashDomain::Plain)
+    }
+}
+
+impl Default for MerkleMountainRange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: MerkleHasher> MerkleMountainRange<H> {
+    /// Empty range with any hasher and hash domain
+    pub fn build(domain: HashDomain) -> Self {
+        MerkleMountainRange { domain, layers: vec![Vec::new()] }
+    }
+
+    /// Append a leaf and return its index
+    ///
+    /// Each append merges the mountains of equal height it completes,
+    /// which costs O(1) hashes on average.
+    pub fn append(&mut self, leaf: &[u8]) -> usize {
+        let index = self.len();
+        self.layers[0].push(MerkleNode::<H>::leaf_with(leaf, self.domain).hash);
+        let mut height = 0;
+        let mut idx = index;
+        //a right child completes its parent
+        while idx % 2 == 1 {
+            let left = MerkleNode::<H> { hash: self.layers[height][idx-1] };
+            let right = MerkleNode { hash: self.layers[height][idx] };
+            let parent = MerkleNode::parent_with(&left, &right, self.domain).hash;
+            if self.layers.len() == height + 1 { self.layers.push(Vec::new()) }
+            self.layers[height+1].push(parent);
+            height += 1;
+            idx /= 2;
+        }
+        index
+    }
+
+    /// Return the number of leaves
+    pub fn len(&self) -> usize {
+        self.layers[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers[0].is_empty()
+    }
+
+    /// Return the current peaks, highest mountain first
+    pub fn peaks(&self) -> Vec<H::Digest> {
+        self.peaks_at(self.len()).unwrap()
+    }
+
+    /// Return the peaks the range had when it held `size` leaves
+    pub fn peaks_at(&self, size: usize) -> Option<Vec<H::Digest>> {
+        if size > self.len() { return None; }
+        Some(mountains(size).map(|(height, start)| self.layers[height][start >> height]).collect())
+    }
+
+    /// Return the bagged root, None while the range is empty
+    pub fn root(&self) -> Option<H::Digest> {
+        self.root_at(self.len())
+    }
+
+    /// Return the root the range had when it held `size` leaves
+    pub fn root_at(&self, size: usize) -> Option<H::Digest> {
+        bag_peaks::<H>(&self.peaks_at(size)?, self.domain)
+    }
+
+    /// Inclusion proof of leaf `leaf_index` against `root_at(size)`
+    ///
+    /// The path climbs the leaf's mountain, then goes through the bagging:
+    /// first the bag of the lower peaks on the right, then each higher peak on the left.
+    /// It verifies with `verify_proof`.
+    pub fn proof(&self, leaf_index: usize, size: usize) -> Option<Vec<(H::Digest, SiblingDirection)>> {
+        if leaf_index >= size || size > self.len() { return None; }
+        let peaks = self.peaks_at(size)?;
+        let (pos, (height, _)) = mountains(size)
+            .enumerate()
+            .find(|(_, (height, start))| leaf_index < start + (1 << height))?;
+
+        let mut path = Vec::new();
+        for level in 0..height {
+            let idx = leaf_index >> level;
+            if idx % 2 == 1 {
+                path.push((self.layers[level][idx-1], SiblingDirection::Left))
+            } else {
+                path.push((self.layers[level][idx+1], SiblingDirection::Right))
+            }
+        }
+        if let Some(right_bag) = bag_peaks::<H>(&peaks[pos+1..], self.domain) {
+            path.push((right_bag, SiblingDirection::Right));
+        }
+        for peak in peaks[..pos].iter().rev() {
+            path.push((*peak, SiblingDirection::Left));
+        }
+        Some(path)
+    }
+
+    /// Compact form of the current state: leaf count (u64, little-endian) then the peaks
+    ///
+    /// This is all a light client needs to recompute the root, see `decode_peaks`.
+    pub fn peaks_bytes(&self) -> Vec<u8> {
+        let mut bytes = (self.len() as u64).to_le_bytes().to_vec();
+        for peak in self.peaks() {
+            bytes.extend_from_slice(peak.as_ref());
+        }
+        bytes
+    }
+}
+
+//(height, first leaf) of each mountain of a range of `size` leaves, highest first
+fn mountains(size: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..usize::BITS as usize)
+        .rev()
+        .filter(move |height| size >> height & 1 == 1)
+        .map(move |height| (height, size >> (height + 1) << (height + 1)))
+}
+
+/// Fold peaks right to left into one root: `p0 || (p1 || (... || pk))`
+pub fn bag_peaks<H: MerkleHasher>(peaks: &[H::Digest], domain: HashDomain) -> Option<H::Digest> {
+    let (last, rest) = peaks.split_last()?;
+    let mut acc = MerkleNode::<H> { hash: *last };
+    for peak in rest.iter().rev() {
+        acc = MerkleNode::parent_with(&MerkleNode { hash: *peak }, &acc, domain);
+    }
+    Some(acc.hash)
+}
+
+/// Parse the output of `peaks_bytes` into (leaf count, peaks)
+///
+/// Returns None if the length doesn't match one peak per set bit of the leaf count.
+pub fn decode_peaks<H: MerkleHasher>(bytes: &[u8]) -> Option<(usize, Vec<H::Digest>)> {
+    let (count, rest) = bytes.split_first_chunk::<8>()?;
+    let size = usize::try_from(u64::from_le_bytes(*count)).ok()?;
+    if rest.len() != size.count_ones() as usize * H::DIGEST_SIZE { return None; }
+    let peaks = rest.chunks_exact(H::DIGEST_SIZE)
+        .map(|chunk| H::Digest::try_from(chunk).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some((size, peaks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Keccak256Hasher;
+    use crate::merkle_tree::{verify_proof, verify_proof_with, MerkleTree};
+
+    fn events(size: usize) -> Vec<Vec<u8>> {
+        (0..size).map(|i| format!("event_{}", i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_empty_range() {
+        let mmr = MerkleMountainRange::new();
+        assert!(mmr.is_empty());
+        assert!(mmr.root().is_none());
+        assert!(mmr.peaks().is_empty());
+        assert!(mmr.proof(0, 0).is_none());
+    }
+
+    #[test]
+    fn test_peaks_follow_binary_decomposition() {
+        let mut mmr = MerkleMountainRange::new();
+        for (i, event) in events(11).iter().enumerate() {
+            assert_eq!(mmr.append(event), i);
+            assert_eq!(mmr.peaks().len(), (i + 1).count_ones() as usize);
+        }
+        // 11 = 8 + 2 + 1
+        assert_eq!(mmr.peaks()[2], MerkleNode::leaf(b"event_10").hash);
+    }
+
+    #[test]
+    fn test_root_matches_merkle_tree() {
+        let data = events(20);
+        let mut mmr = MerkleMountainRange::new();
+        for (i, event) in data.iter().enumerate() {
+            mmr.append(event);
+            let tree = MerkleTree::new(data[..=i].iter().map(|e| e.as_slice()).collect());
+            assert_eq!(mmr.root(), Some(tree.root()), "size {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn test_historical_proofs() {
+        let data = events(13);
+        let mut mmr = MerkleMountainRange::new();
+        for event in &data {
+            mmr.append(event);
+        }
+        for size in 1..=data.len() {
+            let root = mmr.root_at(size).unwrap();
+            for (idx, event) in data[..size].iter().enumerate() {
+                let proof = mmr.proof(idx, size).unwrap();
+                assert!(verify_proof(event, &proof, root), "leaf {idx} at size {size}");
+                assert!(!verify_proof(b"forged", &proof, root));
+            }
+            assert!(mmr.proof(size, size).is_none());
+        }
+        assert!(mmr.root_at(14).is_none());
+    }
+
+    #[test]
+    fn test_old_proof_against_new_root_fails() {
+        let mut mmr = MerkleMountainRange::new();
+        for event in events(5) {
+            mmr.append(&event);
+        }
+        let old_proof = mmr.proof(4, 5).unwrap();
+        mmr.append(b"event_5");
+        assert!(!verify_proof(b"event_4", &old_proof, mmr.root().unwrap()));
+        assert!(verify_proof(b"event_4", &old_proof, mmr.root_at(5).unwrap()));
+    }
+
+    #[test]
+    fn test_peaks_bytes_round_trip() {
+        let mut mmr = MerkleMountainRange::new();
+        for event in events(7) {
+            mmr.append(&event);
+        }
+        let bytes = mmr.peaks_bytes();
+        assert_eq!(bytes.len(), 8 + 3 * 32);
+        let (size, peaks) = decode_peaks::<Sha256Hasher>(&bytes).unwrap();
+        assert_eq!(size, 7);
+        assert_eq!(peaks, mmr.peaks());
+        assert_eq!(bag_peaks::<Sha256Hasher>(&peaks, HashDomain::Plain), mmr.root());
+
+        assert!(decode_peaks::<Sha256Hasher>(&bytes[..bytes.len() - 1]).is_none());
+        assert!(decode_peaks::<Sha256Hasher>(&bytes[..7]).is_none());
+    }
+
+    #[test]
+    fn test_generic_hasher_and_domain() {
+        let mut mmr = MerkleMountainRange::<Keccak256Hasher>::build(HashDomain::Rfc6962);
+        for event in events(6) {
+            mmr.append(&event);
+        }
+        let proof = mmr.proof(5, 6).unwrap();
+        assert!(verify_proof_with::<Keccak256Hasher>(b"event_5", &proof, mmr.root().unwrap(), HashDomain::Rfc6962));
+    }
+}
@@ -0,0 +1,503 @@
+use std::fmt;
+
+use crate::merkle_hasher::{Keccak256Hasher, MerkleHasher};
+use crate::merkle_tree::Hash;
+use crate::rlp::{self, RlpItem};
+
+/// Root of the empty trie: keccak256(rlp(""))
+pub const EMPTY_TRIE_ROOT: Hash = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+
+//A trie node; paths are in nibbles (half-bytes), one per level of a branch
+#[derive(Clone, Debug, Default)]
+enum Node {
+    #[default]
+    Empty,
+    Leaf { path: Vec<u8>, value: Vec<u8> },
+    Extension { path: Vec<u8>, child: Box<Node> },
+    Branch { children: Box<[Node; 16]>, value: Option<Vec<u8>> },
+}
+
+/// Ethereum Merkle Patricia Trie, hashed with Keccak-256
+///
+/// Keys are used as given: Ethereum's state and storage tries are "secure"
+/// tries keyed by `secure_key(key)`. Like in Ethereum an empty value is no value,
+/// so inserting one deletes the key.
+#[derive(Clone, Debug, Default)]
+pub struct PatriciaTrie {
+    root: Node,
+    len: usize,
+}
+
+impl PatriciaTrie {
+    /// Empty trie
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the root hash, `EMPTY_TRIE_ROOT` for an empty trie
+    pub fn root(&self) -> Hash {
+        keccak(&encode(&self.root))
+    }
+
+    /// Return the number of keys
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the value stored under `key`
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let mut node = &self.root;
+        let nibbles = to_nibbles(key);
+        let mut path = nibbles.as_slice();
+        loop {
+            match node {
+                Node::Empty => return None,
+                Node::Leaf { path: leaf_path, value } => return (leaf_path == path).then_some(value.as_slice()),
+                Node::Extension { path: ext_path, child } => {
+                    path = path.strip_prefix(ext_path.as_slice())?;
+                    node = child;
+                }
+                Node::Branch { children, value } => match path.split_first() {
+                    None => return value.as_deref(),
+                    Some((nibble, rest)) => {
+                        node = &children[*nibble as usize];
+                        path = rest;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Store `value` under `key` and return the previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        if value.is_empty() { return self.remove(key); }
+        let old = self.remove(key);
+        let root = std::mem::take(&mut self.root);
+        self.root = insert(root, &to_nibbles(key), value.to_vec());
+        self.len += 1;
+        old
+    }
+
+    /// Remove `key` and return its value
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let root = std::mem::take(&mut self.root);
+        let (root, old) = remove(root, &to_nibbles(key));
+        self.root = root;
+        if old.is_some() { self.len -= 1 }
+        old
+    }
+
+    /// `eth_getProof`-style proof for `key`: the RLP of every hashed node on its path, root first
+    ///
+    /// Nodes shorter than 32 bytes are embedded in their parent and not listed.
+    /// The proof shows the value of `key`, or its absence.
+    pub fn proof(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let nibbles = to_nibbles(key);
+        let mut path = nibbles.as_slice();
+        let mut proof = vec![encode(&self.root)];
+        let mut node = &self.root;
+        loop {
+            let next = match node {
+                Node::Empty | Node::Leaf { .. } => None,
+                Node::Extension { path: ext_path, child } => path.strip_prefix(ext_path.as_slice()).map(|rest| (&**child, rest)),
+                Node::Branch { children, .. } => path.split_first().map(|(nibble, rest)| (&children[*nibble as usize], rest)),
+            };
+            let Some((child, rest)) = next else { return proof };
+            let encoded = encode(child);
+            if encoded.len() >= 32 { proof.push(encoded) }
+            node = child;
+            path = rest;
+        }
+    }
+}
+
+/// Key of the state and storage tries: keccak256 of the address or slot
+pub fn secure_key(key: &[u8]) -> Hash {
+    keccak(key)
+}
+
+/// Why a trie proof was rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrieProofError {
+    /// The path needs a node the proof doesn't hold
+    MissingNode,
+    /// Node `index` of the proof doesn't have the hash its parent points to
+    HashMismatch(usize),
+    /// Invalid RLP or node structure
+    InvalidNode,
+    /// The proof holds nodes off the path of the key
+    UnusedNodes,
+}
+
+impl fmt::Display for TrieProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrieProofError::MissingNode => write!(f, "proof is missing a node"),
+            TrieProofError::HashMismatch(index) => write!(f, "proof node {index} does not match its hash"),
+            TrieProofError::InvalidNode => write!(f, "invalid trie node"),
+            TrieProofError::UnusedNodes => write!(f, "proof holds unused nodes"),
+        }
+    }
+}
+
+impl std::error::Error for TrieProofError {}
+
+/// Check a proof from `PatriciaTrie::proof` (or `eth_getProof`) against `root`
+///
+/// Returns the value stored under `key`, or None if the proof shows it is absent.
+pub fn verify_trie_proof(root: &Hash, key: &[u8], proof: &[Vec<u8>]) -> Result<Option<Vec<u8>>, TrieProofError> {
+    let nibbles = to_nibbles(key);
+    let mut path = nibbles.as_slice();
+    let mut nodes = proof.iter().enumerate();
+    let mut expected = *root;
+    //hashed node to fetch from the proof, or node embedded in its parent
+    let mut node = fetch(&mut nodes, &expected)?;
+    loop {
+        let child = match &node {
+            RlpItem::List(items) if items.len() == 17 => {
+                let Some((nibble, rest)) = path.split_first() else {
+                    let value = bytes(&items[16])?;
+                    return finish(nodes, (!value.is_empty()).then(|| value.to_vec()));
+                };
+                path = rest;
+                items[*nibble as usize].clone()
+            }
+            RlpItem::List(items) if items.len() == 2 => {
+                let (is_leaf, node_path) = decode_hex_prefix(bytes(&items[0])?).ok_or(TrieProofError::InvalidNode)?;
+                if is_leaf {
+                    let value = (path == node_path.as_slice()).then(|| bytes(&items[1]).map(<[u8]>::to_vec)).transpose()?;
+                    return finish(nodes, value);
+                }
+                let Some(rest) = path.strip_prefix(node_path.as_slice()) else { return finish(nodes, None) };
+                path = rest;
+                items[1].clone()
+            }
+            _ => return Err(TrieProofError::InvalidNode),
+        };
+        node = match child {
+            RlpItem::Bytes([]) => return finish(nodes, None),
+            RlpItem::Bytes(hash) => {
+                expected = hash.try_into().map_err(|_| TrieProofError::InvalidNode)?;
+                fetch(&mut nodes, &expected)?
+            }
+            embedded => embedded,
+        };
+    }
+}
+
+//Next proof node, which must hash to `expected`
+fn fetch<'a>(nodes: &mut impl Iterator<Item = (usize, &'a Vec<u8>)>, expected: &Hash) -> Result<RlpItem<'a>, TrieProofError> {
+    let (index, encoded) = nodes.next().ok_or(TrieProofError::MissingNode)?;
+    if keccak(encoded) != *expected { return Err(TrieProofError::HashMismatch(index)); }
+    rlp::decode(encoded).ok_or(TrieProofError::InvalidNode)
+}
+
+//Every proof node must have been used
+fn finish<'a>(mut nodes: impl Iterator<Item = (usize, &'a Vec<u8>)>, value: Option<Vec<u8>>) -> Result<Option<Vec<u8>>, TrieProofError> {
+    match nodes.next() {
+        Some(_) => Err(TrieProofError::UnusedNodes),
+        None => Ok(value),
+    }
+}
+
+fn bytes<'a>(item: &RlpItem<'a>) -> Result<&'a [u8], TrieProofError> {
+    match item {
+        RlpItem::Bytes(bytes) => Ok(bytes),
+        RlpItem::List(_) => Err(TrieProofError::InvalidNode),
+    }
+}
+
+fn keccak(data: &[u8]) -> Hash {
+    Keccak256Hasher::hash(&[data])
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|byte| [byte >> 4, byte & 0x0f]).collect()
+}
+
+//Hex-prefix encoding: a flag nibble (2 = leaf, +1 = odd length), padded to whole bytes
+fn hex_prefix(nibbles: &[u8], is_leaf: bool) -> Vec<u8> {
+    let flag = if is_leaf {2} else {0};
+    let mut out = Vec::with_capacity(nibbles.len() / 2 + 1);
+    let rest = if nibbles.len() % 2 == 1 {
+        out.push((flag + 1) << 4 | nibbles[0]);
+        &nibbles[1..]
+    } else {
+        out.push(flag << 4);
+        nibbles
+    };
+    out.extend(rest.chunks(2).map(|pair| pair[0] << 4 | pair[1]));
+    out
+}
+
+//Inverse of `hex_prefix`: (is_leaf, nibbles)
+fn decode_hex_prefix(bytes: &[u8]) -> Option<(bool, Vec<u8>)> {
+    let (first, rest) = bytes.split_first()?;
+    let flag = first >> 4;
+    if flag > 3 { return None; }
+    let mut nibbles = Vec::with_capacity(rest.len() * 2 + 1);
+    if flag & 1 == 1 {
+        nibbles.push(first & 0x0f);
+    } else if first & 0x0f != 0 {
+        return None;
+    }
+    nibbles.extend(to_nibbles(rest));
+    Some((flag & 2 == 2, nibbles))
+}
+
+//RLP of a node
+fn encode(node: &Node) -> Vec<u8> {
+    match node {
+        Node::Empty => rlp::encode_bytes(b""),
+        Node::Leaf { path, value } => rlp::encode_list(&[rlp::encode_bytes(&hex_prefix(path, true)), rlp::encode_bytes(value)]),
+        Node::Extension { path, child } => rlp::encode_list(&[rlp::encode_bytes(&hex_prefix(path, false)), reference(child)]),
+        Node::Branch { children, value } => {
+            let mut items: Vec<Vec<u8>> = children.iter().map(reference).collect();
+            items.push(rlp::encode_bytes(value.as_deref().unwrap_or_default()));
+            rlp::encode_list(&items)
+        }
+    }
+}
+
+//How a parent points to a child: embedded below 32 bytes, by hash otherwise
+fn reference(node: &Node) -> Vec<u8> {
+    let encoded = encode(node);
+    match node {
+        Node::Empty => encoded,
+        _ if encoded.len() < 32 => encoded,
+        _ => rlp::encode_bytes(&keccak(&encoded)),
+    }
+}
+
+fn empty_branch() -> Node {
+    Node::Branch { children: Box::default(), value: None }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+//Put the branch holding `node` under an extension of `path`, if any
+fn under_extension(path: &[u8], node: Node) -> Node {
+    if path.is_empty() { node } else { Node::Extension { path: path.to_vec(), child: Box::new(node) } }
+}
+
+//Insert a key that is not in `node`
+fn insert(node: Node, path: &[u8], value: Vec<u8>) -> Node {
+    match node {
+        Node::Empty => Node::Leaf { path: path.to_vec(), value },
+        Node::Leaf { path: leaf_path, value: leaf_value } => {
+            //split into a branch where the two paths diverge
+            let common = common_prefix(&leaf_path, path);
+            let branch = insert(empty_branch(), &leaf_path[common..], leaf_value);
+            under_extension(&path[..common], insert(branch, &path[common..], value))
+        }
+        Node::Extension { path: ext_path, child } => {
+            let common = common_prefix(&ext_path, path);
+            if common == ext_path.len() {
+                return Node::Extension { child: Box::new(insert(*child, &path[common..], value)), path: ext_path };
+            }
+            let mut branch = empty_branch();
+            if let Node::Branch { children, .. } = &mut branch {
+                children[ext_path[common] as usize] = under_extension(&ext_path[common + 1..], *child);
+            }
+            under_extension(&path[..common], insert(branch, &path[common..], value))
+        }
+        Node::Branch { mut children, value: branch_value } => match path.split_first() {
+            None => Node::Branch { children, value: Some(value) },
+            Some((nibble, rest)) => {
+                let slot = &mut children[*nibble as usize];
+                *slot = insert(std::mem::take(slot), rest, value);
+                Node::Branch { children, value: branch_value }
+            }
+        },
+    }
+}
+
+//Remove `path` from `node`, keep the result in canonical form, return the removed value
+fn remove(node: Node, path: &[u8]) -> (Node, Option<Vec<u8>>) {
+    match node {
+        Node::Empty => (Node::Empty, None),
+        Node::Leaf { path: leaf_path, value } if leaf_path == path => (Node::Empty, Some(value)),
+        leaf @ Node::Leaf { .. } => (leaf, None),
+        Node::Extension { path: ext_path, child } => match path.strip_prefix(ext_path.as_slice()) {
+            Some(rest) => {
+                let (child, old) = remove(*child, rest);
+                (join_paths(ext_path, child), old)
+            }
+            None => (Node::Extension { path: ext_path, child }, None),
+        },
+        Node::Branch { mut children, value } => {
+            let (value, old) = match path.split_first() {
+                None => (None, value),
+                Some((nibble, rest)) => {
+                    let slot = &mut children[*nibble as usize];
+                    let (child, old) = remove(std::mem::take(slot), rest);
+                    *slot = child;
+                    (value, old)
+                }
+            };
+            if old.is_none() { return (Node::Branch { children, value }, None); }
+            (collapse_branch(children, value), old)
+        }
+    }
+}
+
+//A branch with a single entry left is not canonical: merge it upwards
+fn collapse_branch(mut children: Box<[Node; 16]>, value: Option<Vec<u8>>) -> Node {
+    let mut used = children.iter().enumerate().filter(|(_, child)| !matches!(child, Node::Empty)).map(|(i, _)| i);
+    match (used.next(), used.next(), value) {
+        (None, _, None) => Node::Empty,
+        (None, _, Some(value)) => Node::Leaf { path: Vec::new(), value },
+        (Some(nibble), None, None) => join_paths(vec![nibble as u8], std::mem::take(&mut children[nibble])),
+        (_, _, value) => Node::Branch { children, value },
+    }
+}
+
+//Prepend `path` to `child`, merging consecutive path segments
+fn join_paths(mut path: Vec<u8>, child: Node) -> Node {
+    match child {
+        Node::Empty => Node::Empty,
+        Node::Leaf { path: rest, value } => {
+            path.extend(rest);
+            Node::Leaf { path, value }
+        }
+        Node::Extension { path: rest, child } => {
+            path.extend(rest);
+            Node::Extension { path, child }
+        }
+        branch => Node::Extension { path, child: Box::new(branch) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(entries: &[(&str, &str)]) -> PatriciaTrie {
+        let mut trie = PatriciaTrie::new();
+        for (key, value) in entries {
+            trie.insert(key.as_bytes(), value.as_bytes());
+        }
+        trie
+    }
+
+    // Vectors from the Ethereum test suite (trieanyorder.json)
+    #[test]
+    fn test_known_roots() {
+        assert_eq!(PatriciaTrie::new().root(), EMPTY_TRIE_ROOT);
+        assert_eq!(hex::encode(EMPTY_TRIE_ROOT), "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421");
+        let dogs = trie(&[("doe", "reindeer"), ("dog", "puppy"), ("dogglesworth", "cat")]);
+        assert_eq!(hex::encode(dogs.root()), "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3");
+        let foo = trie(&[("foo", "bar"), ("food", "bass")]);
+        assert_eq!(hex::encode(foo.root()), "17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3");
+        let puppy = trie(&[("do", "verb"), ("horse", "stallion"), ("doge", "coin"), ("dog", "puppy")]);
+        assert_eq!(hex::encode(puppy.root()), "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84");
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut trie = trie(&[("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")]);
+        assert_eq!(trie.len(), 4);
+        assert_eq!(trie.get(b"dog"), Some(&b"puppy"[..]));
+        assert_eq!(trie.get(b"d"), None);
+        assert_eq!(trie.get(b"doges"), None);
+        assert_eq!(trie.insert(b"dog", b"hound"), Some(b"puppy".to_vec()));
+        assert_eq!(trie.get(b"dog"), Some(&b"hound"[..]));
+        assert_eq!(trie.len(), 4);
+
+        assert_eq!(trie.remove(b"dog"), Some(b"hound".to_vec()));
+        assert_eq!(trie.remove(b"dog"), None);
+        assert_eq!(trie.get(b"doge"), Some(&b"coin"[..]));
+        assert_eq!(trie.len(), 3);
+    }
+
+    #[test]
+    fn test_root_is_order_independent_and_canonical() {
+        let entries = [("do", "verb"), ("horse", "stallion"), ("doge", "coin"), ("dog", "puppy")];
+        let forward = trie(&entries);
+        let mut reversed = entries;
+        reversed.reverse();
+        assert_eq!(trie(&reversed).root(), forward.root());
+
+        // Removing a key gives the root of the trie built without it
+        let mut removed = trie(&entries);
+        removed.insert(b"dogglesworth", b"cat");
+        removed.insert(b"dogglesworth", b"");
+        assert_eq!(removed.root(), forward.root());
+        for (key, _) in entries {
+            removed.remove(key.as_bytes());
+        }
+        assert_eq!(removed.root(), EMPTY_TRIE_ROOT);
+    }
+
+    #[test]
+    fn test_proofs_of_presence() {
+        let mut trie = PatriciaTrie::new();
+        for i in 0..200u32 {
+            trie.insert(&secure_key(&i.to_be_bytes()), format!("value_{i}").as_bytes());
+        }
+        let root = trie.root();
+        for i in [0u32, 1, 57, 199] {
+            let key = secure_key(&i.to_be_bytes());
+            let proof = trie.proof(&key);
+            assert_eq!(verify_trie_proof(&root, &key, &proof), Ok(Some(format!("value_{i}").into_bytes())));
+        }
+    }
+
+    #[test]
+    fn test_proofs_of_absence() {
+        let trie = trie(&[("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")]);
+        let root = trie.root();
+        for absent in ["d", "dot", "doges", "horses", "cat", ""] {
+            let proof = trie.proof(absent.as_bytes());
+            assert_eq!(verify_trie_proof(&root, absent.as_bytes(), &proof), Ok(None), "{absent}");
+        }
+        // Embedded nodes: every value is found from the nodes listed in the proof
+        for (key, value) in [("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")] {
+            let proof = trie.proof(key.as_bytes());
+            assert_eq!(verify_trie_proof(&root, key.as_bytes(), &proof), Ok(Some(value.as_bytes().to_vec())));
+        }
+    }
+
+    #[test]
+    fn test_tampered_proofs_rejected() {
+        let mut trie = PatriciaTrie::new();
+        for i in 0..50u8 {
+            trie.insert(&secure_key(&[i]), &[i; 40]);
+        }
+        let key = secure_key(&[7]);
+        let proof = trie.proof(&key);
+        assert!(proof.len() >= 2);
+        let root = trie.root();
+
+        let mut tampered = proof.clone();
+        let last = tampered.last_mut().unwrap();
+        let end = last.len() - 1;
+        last[end] ^= 1;
+        assert_eq!(verify_trie_proof(&root, &key, &tampered), Err(TrieProofError::HashMismatch(proof.len() - 1)));
+        assert_eq!(verify_trie_proof(&root, &key, &proof[..proof.len() - 1]), Err(TrieProofError::MissingNode));
+        let mut extra = proof.clone();
+        extra.push(proof[0].clone());
+        assert_eq!(verify_trie_proof(&root, &key, &extra), Err(TrieProofError::UnusedNodes));
+        assert_eq!(verify_trie_proof(&[0; 32], &key, &proof), Err(TrieProofError::HashMismatch(0)));
+    }
+
+    #[test]
+    fn test_hex_prefix() {
+        assert_eq!(hex_prefix(&[1, 2, 3, 4, 5], false), vec![0x11, 0x23, 0x45]);
+        assert_eq!(hex_prefix(&[0, 1, 2, 3, 4, 5], false), vec![0x00, 0x01, 0x23, 0x45]);
+        assert_eq!(hex_prefix(&[0x0f, 1, 0x0c, 0x0b, 8], true), vec![0x3f, 0x1c, 0xb8]);
+        assert_eq!(hex_prefix(&[], true), vec![0x20]);
+        for (nibbles, leaf) in [(vec![1, 2, 3], true), (vec![], false), (vec![9, 0], true)] {
+            assert_eq!(decode_hex_prefix(&hex_prefix(&nibbles, leaf)), Some((leaf, nibbles)));
+        }
+    }
+}
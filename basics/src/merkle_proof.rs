@@ -0,0 +1,491 @@
+use std::fmt;
+
+use serde_json::{json, Map, Value};
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{check_shape, verify_proof_detailed, HashDomain, MerkleError, MerkleTree, OddNodePolicy, SiblingDirection, TreeConfig};
+
+/// Current version of the proof wire format
+pub const PROOF_VERSION: u8 = 2;
+
+//version + hash id + domain + policy + pad length + leaf index + tree size + path length
+const HEADER_LEN: usize = 1 + 1 + 1 + 1 + 2 + 8 + 8 + 2;
+
+/// Inclusion proof with everything needed to ship it between services
+///
+/// The odd-node policy is part of the proof, so the path's length and sibling sides
+/// are checked against `leaf_index` and `tree_size` when decoding and verifying.
+///
+/// Binary layout (version 2, integers little-endian):
+/// `version: u8 | hash id: u8 | domain: u8 | policy: u8 | pad len: u16 | leaf_index: u64 |
+/// tree_size: u64 | path len: u16 | pad leaf | directions: one bit per step, LSB first,
+/// 1 = sibling on the right | sibling hashes`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof<H: MerkleHasher = Sha256Hasher> {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub domain: HashDomain,
+    pub odd_policy: OddNodePolicy,
+    pub path: Vec<(H::Digest, SiblingDirection)>,
+}
+
+/// Why an encoded proof was rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProofFormatError {
+    UnsupportedVersion(u8),
+    /// The proof was made with another hash function than the one expected
+    HashMismatch { expected: String, found: String },
+    UnknownDomain(String),
+    UnknownPolicy(String),
+    /// Wrong number of bytes for the announced path
+    InvalidLength { expected: usize, found: usize },
+    /// Unused direction bits must be zero, so that a proof has a single encoding
+    NonCanonicalDirections,
+    IndexOutOfRange { leaf_index: u64, tree_size: u64 },
+    PathTooLong(usize),
+    /// The pad leaf is longer than the `u16` length field allows
+    PadTooLong(usize),
+    /// The path doesn't have the length or the sibling sides of `leaf_index` in `tree_size` leaves
+    InvalidShape(MerkleError),
+    InvalidHex,
+    InvalidJson(String),
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for ProofFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofFormatError::UnsupportedVersion(v) => write!(f, "unsupported proof version {v}"),
+            ProofFormatError::HashMismatch { expected, found } => write!(f, "proof uses hash {found}, expected {expected}"),
+            ProofFormatError::UnknownDomain(d) => write!(f, "unknown hash domain {d}"),
+            ProofFormatError::UnknownPolicy(p) => write!(f, "unknown odd-node policy {p}"),
+            ProofFormatError::InvalidLength { expected, found } => write!(f, "expected {expected} bytes, found {found}"),
+            ProofFormatError::NonCanonicalDirections => write!(f, "unused direction bits are set"),
+            ProofFormatError::IndexOutOfRange { leaf_index, tree_size } => write!(f, "leaf index {leaf_index} out of range for {tree_size} leaves"),
+            ProofFormatError::PathTooLong(len) => write!(f, "path of {len} steps does not fit the format"),
+            ProofFormatError::PadTooLong(len) => write!(f, "pad leaf of {len} bytes does not fit the format"),
+            ProofFormatError::InvalidShape(e) => write!(f, "path does not fit the leaf position: {e}"),
+            ProofFormatError::InvalidHex => write!(f, "invalid hex string"),
+            ProofFormatError::InvalidJson(e) => write!(f, "invalid JSON: {e}"),
+            ProofFormatError::MissingField(name) => write!(f, "missing field `{name}`"),
+            ProofFormatError::InvalidField(name) => write!(f, "invalid field `{name}`"),
+        }
+    }
+}
+
+impl std::error::Error for ProofFormatError {}
+
+impl<H: MerkleHasher> MerkleProof<H> {
+    /// Proof of leaf `leaf_index` of `tree`, None if the index is out of range or the tree isn't binary
+    pub fn from_tree(tree: &MerkleTree<H>, leaf_index: usize) -> Option<Self> {
+        Some(MerkleProof {
+            leaf_index: leaf_index as u64,
+            tree_size: tree.num_leaves() as u64,
+            domain: tree.domain(),
+            odd_policy: tree.policy().clone(),
+            path: tree.proof_path(leaf_index)?,
+        })
+    }
+
+    /// Return the hash domain and odd-node policy of the proven tree
+    pub fn config(&self) -> TreeConfig {
+        TreeConfig { odd_policy: self.odd_policy.clone(), domain: self.domain, arity: 2 }
+    }
+
+    /// Check `leaf` against `root` at position `leaf_index` of a tree of `tree_size` leaves
+    pub fn verify(&self, leaf: &[u8], root: H::Digest) -> bool {
+        self.verify_detailed(leaf, root).is_ok()
+    }
+
+    /// Same as `verify`, explaining why the proof is rejected
+    pub fn verify_detailed(&self, leaf: &[u8], root: H::Digest) -> Result<(), MerkleError> {
+        let (leaf_index, tree_size) = positions(self.leaf_index, self.tree_size)?;
+        verify_proof_detailed::<H>(leaf, leaf_index, tree_size, &self.path, root, &self.config())
+    }
+
+    /// Compact binary encoding (see the type documentation for the layout),
+    /// failing if the pad leaf or the path is too long for its `u16` length field
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProofFormatError> {
+        let path_len = u16::try_from(self.path.len()).map_err(|_| ProofFormatError::PathTooLong(self.path.len()))?;
+        let (policy, pad) = policy_id(&self.odd_policy);
+        let pad_len = u16::try_from(pad.len()).map_err(|_| ProofFormatError::PadTooLong(pad.len()))?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + pad.len() + self.path.len().div_ceil(8) + self.path.len() * H::DIGEST_SIZE);
+        bytes.push(PROOF_VERSION);
+        bytes.push(H::ID);
+        bytes.push(domain_id(self.domain));
+        bytes.push(policy);
+        bytes.extend_from_slice(&pad_len.to_le_bytes());
+        bytes.extend_from_slice(&self.leaf_index.to_le_bytes());
+        bytes.extend_from_slice(&self.tree_size.to_le_bytes());
+        bytes.extend_from_slice(&path_len.to_le_bytes());
+        bytes.extend_from_slice(pad);
+
+        let mut directions = vec![0u8; self.path.len().div_ceil(8)];
+        for (step, (_, direction)) in self.path.iter().enumerate() {
+            if *direction == SiblingDirection::Right {
+                directions[step / 8] |= 1 << (step % 8);
+            }
+        }
+        bytes.extend_from_slice(&directions);
+        for (sibling, _) in &self.path {
+            bytes.extend_from_slice(sibling.as_ref());
+        }
+        Ok(bytes)
+    }
+
+    /// Parse `to_bytes` output, rejecting anything that isn't its exact encoding
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProofFormatError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(ProofFormatError::InvalidLength { expected: HEADER_LEN, found: bytes.len() });
+        }
+        if bytes[0] != PROOF_VERSION { return Err(ProofFormatError::UnsupportedVersion(bytes[0])); }
+        if bytes[1] != H::ID {
+            return Err(ProofFormatError::HashMismatch { expected: H::ID.to_string(), found: bytes[1].to_string() });
+        }
+        let domain = domain_from_id(bytes[2]).ok_or(ProofFormatError::UnknownDomain(bytes[2].to_string()))?;
+        let pad_len = u16::from_le_bytes(bytes[4..6].try_into().unwrap()) as usize;
+        let leaf_index = u64::from_le_bytes(bytes[6..14].try_into().unwrap());
+        let tree_size = u64::from_le_bytes(bytes[14..22].try_into().unwrap());
+        let path_len = u16::from_le_bytes(bytes[22..24].try_into().unwrap()) as usize;
+
+        let bitmap_len = path_len.div_ceil(8);
+        let expected = HEADER_LEN + pad_len + bitmap_len + path_len * H::DIGEST_SIZE;
+        if bytes.len() != expected { return Err(ProofFormatError::InvalidLength { expected, found: bytes.len() }); }
+        let (pad, rest) = bytes[HEADER_LEN..].split_at(pad_len);
+        let odd_policy = policy_from_id(bytes[3], pad).ok_or(ProofFormatError::UnknownPolicy(bytes[3].to_string()))?;
+        let (directions, siblings) = rest.split_at(bitmap_len);
+        if !path_len.is_multiple_of(8) && directions[bitmap_len - 1] >> (path_len % 8) != 0 {
+            return Err(ProofFormatError::NonCanonicalDirections);
+        }
+
+        let path = siblings.chunks_exact(H::DIGEST_SIZE)
+            .enumerate()
+            .map(|(step, chunk)| {
+                let sibling = H::Digest::try_from(chunk).ok().expect("chunk has the digest size");
+                let direction = if directions[step / 8] >> (step % 8) & 1 == 1 {SiblingDirection::Right} else {SiblingDirection::Left};
+                (sibling, direction)
+            })
+            .collect();
+        Self::checked(leaf_index, tree_size, TreeConfig { odd_policy, domain, arity: 2 }, path)
+    }
+
+    /// Hex string of `to_bytes`
+    pub fn to_hex(&self) -> Result<String, ProofFormatError> {
+        Ok(hex::encode(self.to_bytes()?))
+    }
+
+    /// Parse `to_hex` output
+    pub fn from_hex(hex_str: &str) -> Result<Self, ProofFormatError> {
+        let bytes = hex::decode(hex_str).map_err(|_| ProofFormatError::InvalidHex)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Human-readable JSON encoding, hashes in hex
+    pub fn to_json(&self) -> String {
+        let path: Vec<Value> = self.path.iter()
+            .map(|(sibling, direction)| json!({
+                "hash": hex::encode(sibling),
+                "side": match direction { SiblingDirection::Left => "left", SiblingDirection::Right => "right" },
+            }))
+            .collect();
+        json!({
+            "version": PROOF_VERSION,
+            "hash": H::NAME,
+            "domain": match self.domain { HashDomain::Plain => "plain", HashDomain::Rfc6962 => "rfc6962" },
+            "policy": match &self.odd_policy {
+                OddNodePolicy::DuplicateLast => json!("duplicate_last"),
+                OddNodePolicy::Promote => json!("promote"),
+                OddNodePolicy::PadWith(empty) => json!({ "pad_with": hex::encode(empty) }),
+            },
+            "leaf_index": self.leaf_index,
+            "tree_size": self.tree_size,
+            "path": path,
+        }).to_string()
+    }
+
+    /// Parse `to_json` output; unknown fields are rejected
+    pub fn from_json(text: &str) -> Result<Self, ProofFormatError> {
+        let value: Value = serde_json::from_str(text).map_err(|e| ProofFormatError::InvalidJson(e.to_string()))?;
+        let object = value.as_object().ok_or(ProofFormatError::InvalidJson("expected an object".to_string()))?;
+        only_fields(object, &["version", "hash", "domain", "policy", "leaf_index", "tree_size", "path"])?;
+
+        let version = field(object, "version")?.as_u64().ok_or(ProofFormatError::InvalidField("version"))?;
+        if version != PROOF_VERSION as u64 {
+            return Err(ProofFormatError::UnsupportedVersion(u8::try_from(version).unwrap_or(u8::MAX)));
+        }
+        let hash = field(object, "hash")?.as_str().ok_or(ProofFormatError::InvalidField("hash"))?;
+        if hash != H::NAME {
+            return Err(ProofFormatError::HashMismatch { expected: H::NAME.to_string(), found: hash.to_string() });
+        }
+        let domain = match field(object, "domain")?.as_str().ok_or(ProofFormatError::InvalidField("domain"))? {
+            "plain" => HashDomain::Plain,
+            "rfc6962" => HashDomain::Rfc6962,
+            other => return Err(ProofFormatError::UnknownDomain(other.to_string())),
+        };
+        let odd_policy = match field(object, "policy")? {
+            Value::String(name) if name == "duplicate_last" => OddNodePolicy::DuplicateLast,
+            Value::String(name) if name == "promote" => OddNodePolicy::Promote,
+            Value::Object(pad) => {
+                only_fields(pad, &["pad_with"])?;
+                let empty = field(pad, "pad_with")?.as_str().and_then(|h| hex::decode(h).ok()).ok_or(ProofFormatError::InvalidField("policy"))?;
+                OddNodePolicy::PadWith(empty)
+            }
+            other => return Err(ProofFormatError::UnknownPolicy(other.to_string())),
+        };
+        let leaf_index = field(object, "leaf_index")?.as_u64().ok_or(ProofFormatError::InvalidField("leaf_index"))?;
+        let tree_size = field(object, "tree_size")?.as_u64().ok_or(ProofFormatError::InvalidField("tree_size"))?;
+
+        let steps = field(object, "path")?.as_array().ok_or(ProofFormatError::InvalidField("path"))?;
+        let mut path = Vec::with_capacity(steps.len());
+        for step in steps {
+            let step = step.as_object().ok_or(ProofFormatError::InvalidField("path"))?;
+            only_fields(step, &["hash", "side"])?;
+            let sibling = field(step, "hash")?.as_str()
+                .and_then(|h| hex::decode(h).ok())
+                .and_then(|bytes| H::Digest::try_from(bytes.as_slice()).ok())
+                .ok_or(ProofFormatError::InvalidField("hash"))?;
+            let direction = match field(step, "side")?.as_str() {
+                Some("left") => SiblingDirection::Left,
+                Some("right") => SiblingDirection::Right,
+                _ => return Err(ProofFormatError::InvalidField("side")),
+            };
+            path.push((sibling, direction));
+        }
+        Self::checked(leaf_index, tree_size, TreeConfig { odd_policy, domain, arity: 2 }, path)
+    }
+
+    //Checks shared by every decoder: the path must be the one of `leaf_index` in `tree_size` leaves
+    fn checked(leaf_index: u64, tree_size: u64, config: TreeConfig, path: Vec<(H::Digest, SiblingDirection)>) -> Result<Self, ProofFormatError> {
+        if leaf_index >= tree_size { return Err(ProofFormatError::IndexOutOfRange { leaf_index, tree_size }); }
+        if path.len() > u16::MAX as usize { return Err(ProofFormatError::PathTooLong(path.len())); }
+        let (index, size) = positions(leaf_index, tree_size).map_err(ProofFormatError::InvalidShape)?;
+        check_shape(index, size, &path, &config).map_err(ProofFormatError::InvalidShape)?;
+        Ok(MerkleProof { leaf_index, tree_size, domain: config.domain, odd_policy: config.odd_policy, path })
+    }
+}
+
+//Wire positions as usize, too big for this platform is an invalid size
+fn positions(leaf_index: u64, tree_size: u64) -> Result<(usize, usize), MerkleError> {
+    let tree_size = usize::try_from(tree_size).map_err(|_| MerkleError::InvalidSize(usize::MAX))?;
+    let leaf_index = usize::try_from(leaf_index).map_err(|_| MerkleError::IndexOutOfRange { index: usize::MAX, num_leaves: tree_size })?;
+    Ok((leaf_index, tree_size))
+}
+
+//Byte encoding of an odd-node policy and its pad leaf, shared with merkle_store and merkle_diff
+pub(crate) fn policy_id(policy: &OddNodePolicy) -> (u8, &[u8]) {
+    match policy {
+        OddNodePolicy::DuplicateLast => (0, &[]),
+        OddNodePolicy::Promote => (1, &[]),
+        OddNodePolicy::PadWith(empty) => (2, empty),
+    }
+}
+
+pub(crate) fn policy_from_id(id: u8, pad: &[u8]) -> Option<OddNodePolicy> {
+    match (id, pad) {
+        (0, []) => Some(OddNodePolicy::DuplicateLast),
+        (1, []) => Some(OddNodePolicy::Promote),
+        (2, pad) => Some(OddNodePolicy::PadWith(pad.to_vec())),
+        _ => None,
+    }
+}
+
+//Byte encoding of a hash domain, shared with merkle_store and merkle_diff
+pub(crate) fn domain_id(domain: HashDomain) -> u8 {
+    match domain {
+        HashDomain::Plain => 0,
+        HashDomain::Rfc6962 => 1,
+    }
+}
+
+pub(crate) fn domain_from_id(id: u8) -> Option<HashDomain> {
+    match id {
+        0 => Some(HashDomain::Plain),
+        1 => Some(HashDomain::Rfc6962),
+        _ => None,
+    }
+}
+
+fn field<'a>(object: &'a Map<String, Value>, name: &'static str) -> Result<&'a Value, ProofFormatError> {
+    object.get(name).ok_or(ProofFormatError::MissingField(name))
+}
+
+fn only_fields(object: &Map<String, Value>, allowed: &[&str]) -> Result<(), ProofFormatError> {
+    match object.keys().find(|key| !allowed.contains(&key.as_str())) {
+        Some(key) => Err(ProofFormatError::InvalidJson(format!("unknown field `{key}`"))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Keccak256Hasher;
+    use crate::merkle_tree::{verify_proof, TreeConfig};
+
+    fn sample() -> (MerkleTree, MerkleProof) {
+        let tree = MerkleTree::new(vec![b"a", b"b", b"c", b"d", b"e"]);
+        let proof = MerkleProof::from_tree(&tree, 2).unwrap();
+        (tree, proof)
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let (tree, proof) = sample();
+        let bytes = proof.to_bytes().unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 1 + proof.path.len() * 32);
+        let decoded = MerkleProof::<Sha256Hasher>::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(b"c", tree.root()));
+        assert!(verify_proof(b"c", &decoded.path, tree.root()));
+        assert!(!decoded.verify(b"x", tree.root()));
+    }
+
+    #[test]
+    fn test_hex_and_json_round_trip() {
+        let (tree, proof) = sample();
+        let from_hex = MerkleProof::<Sha256Hasher>::from_hex(&proof.to_hex().unwrap()).unwrap();
+        assert_eq!(from_hex, proof);
+        let json = proof.to_json();
+        assert!(json.contains("\"hash\":\"sha256\""));
+        let from_json = MerkleProof::<Sha256Hasher>::from_json(&json).unwrap();
+        assert_eq!(from_json, proof);
+        assert!(from_json.verify(b"c", tree.root()));
+    }
+
+    #[test]
+    fn test_every_leaf_and_domain() {
+        let data: Vec<&[u8]> = vec![b"0", b"1", b"2", b"3", b"4", b"5", b"6"];
+        let config = TreeConfig { domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+        let tree = MerkleTree::<Keccak256Hasher>::build(data.clone(), config);
+        for (idx, leaf) in data.iter().enumerate() {
+            let proof = MerkleProof::from_tree(&tree, idx).unwrap();
+            let decoded = MerkleProof::<Keccak256Hasher>::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+            assert!(decoded.verify(leaf, tree.root()));
+            let decoded = MerkleProof::<Keccak256Hasher>::from_json(&proof.to_json()).unwrap();
+            assert!(decoded.verify(leaf, tree.root()));
+        }
+        assert!(MerkleProof::from_tree(&tree, 7).is_none());
+    }
+
+    #[test]
+    fn test_binary_validation() {
+        let (_, proof) = sample();
+        let bytes = proof.to_bytes().unwrap();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = 1;
+        assert_eq!(MerkleProof::<Sha256Hasher>::from_bytes(&wrong_version), Err(ProofFormatError::UnsupportedVersion(1)));
+
+        assert!(matches!(MerkleProof::<Keccak256Hasher>::from_bytes(&bytes), Err(ProofFormatError::HashMismatch { .. })));
+
+        let mut wrong_domain = bytes.clone();
+        wrong_domain[2] = 7;
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&wrong_domain), Err(ProofFormatError::UnknownDomain(_))));
+
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&bytes[..bytes.len() - 1]), Err(ProofFormatError::InvalidLength { .. })));
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&[bytes.clone(), vec![0]].concat()), Err(ProofFormatError::InvalidLength { .. })));
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&bytes[..5]), Err(ProofFormatError::InvalidLength { .. })));
+
+        let mut dirty_bits = bytes.clone();
+        dirty_bits[HEADER_LEN] |= 0x80;
+        assert_eq!(MerkleProof::<Sha256Hasher>::from_bytes(&dirty_bits), Err(ProofFormatError::NonCanonicalDirections));
+
+        let mut wrong_policy = bytes.clone();
+        wrong_policy[3] = 9;
+        assert_eq!(MerkleProof::<Sha256Hasher>::from_bytes(&wrong_policy), Err(ProofFormatError::UnknownPolicy("9".to_string())));
+
+        let mut bad_index = bytes.clone();
+        bad_index[6] = 9;
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&bad_index), Err(ProofFormatError::IndexOutOfRange { .. })));
+
+        assert_eq!(MerkleProof::<Sha256Hasher>::from_hex("zz"), Err(ProofFormatError::InvalidHex));
+    }
+
+    #[test]
+    fn test_json_validation() {
+        let (_, proof) = sample();
+        let good: Value = serde_json::from_str(&proof.to_json()).unwrap();
+        let parse = |value: &Value| MerkleProof::<Sha256Hasher>::from_json(&value.to_string());
+
+        let mut extra = good.clone();
+        extra["extra"] = json!(1);
+        assert!(matches!(parse(&extra), Err(ProofFormatError::InvalidJson(_))));
+
+        let mut missing = good.clone();
+        missing.as_object_mut().unwrap().remove("tree_size");
+        assert_eq!(parse(&missing), Err(ProofFormatError::MissingField("tree_size")));
+
+        let mut wrong_hash = good.clone();
+        wrong_hash["hash"] = json!("md5");
+        assert!(matches!(parse(&wrong_hash), Err(ProofFormatError::HashMismatch { .. })));
+
+        let mut short_sibling = good.clone();
+        short_sibling["path"][0]["hash"] = json!("abcd");
+        assert_eq!(parse(&short_sibling), Err(ProofFormatError::InvalidField("hash")));
+
+        let mut bad_side = good.clone();
+        bad_side["path"][0]["side"] = json!("up");
+        assert_eq!(parse(&bad_side), Err(ProofFormatError::InvalidField("side")));
+
+        let mut bad_policy = good.clone();
+        bad_policy["policy"] = json!("sideways");
+        assert!(matches!(parse(&bad_policy), Err(ProofFormatError::UnknownPolicy(_))));
+
+        let mut negative = good.clone();
+        negative["leaf_index"] = json!(-1);
+        assert_eq!(parse(&negative), Err(ProofFormatError::InvalidField("leaf_index")));
+
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_json("[1, 2]"), Err(ProofFormatError::InvalidJson(_))));
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_json("{"), Err(ProofFormatError::InvalidJson(_))));
+    }
+
+    #[test]
+    fn test_every_policy_round_trips() {
+        let data: Vec<&[u8]> = vec![b"0", b"1", b"2", b"3", b"4"];
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"pad".to_vec())] {
+            let tree = MerkleTree::with_policy(data.clone(), odd_policy.clone());
+            for (idx, leaf) in data.iter().enumerate() {
+                let proof = MerkleProof::from_tree(&tree, idx).unwrap();
+                assert_eq!(proof.odd_policy, odd_policy);
+                let from_bytes = MerkleProof::<Sha256Hasher>::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+                let from_json = MerkleProof::<Sha256Hasher>::from_json(&proof.to_json()).unwrap();
+                assert_eq!(from_bytes, proof);
+                assert_eq!(from_json, proof);
+                assert!(from_bytes.verify(leaf, tree.root()), "{odd_policy:?}, leaf {idx}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_pad_not_encoded() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+        let tree = MerkleTree::with_policy(data, OddNodePolicy::PadWith(vec![7; 70000]));
+        let proof = MerkleProof::from_tree(&tree, 2).unwrap();
+        assert!(proof.verify(b"c", tree.root()));
+        assert_eq!(proof.to_bytes(), Err(ProofFormatError::PadTooLong(70000)));
+        assert_eq!(proof.to_hex(), Err(ProofFormatError::PadTooLong(70000)));
+        // JSON has no length fields, so it still works
+        let from_json = MerkleProof::<Sha256Hasher>::from_json(&proof.to_json()).unwrap();
+        assert_eq!(from_json, proof);
+    }
+
+    #[test]
+    fn test_relabelled_proof_rejected() {
+        let (tree, proof) = sample();
+        // Same path, claimed for another leaf
+        let relabelled = MerkleProof { leaf_index: 4, ..proof.clone() };
+        assert!(!relabelled.verify(b"c", tree.root()));
+        assert!(matches!(relabelled.verify_detailed(b"c", tree.root()), Err(MerkleError::ProofLengthMismatch { .. })));
+        let neighbour = MerkleProof { leaf_index: 3, ..proof.clone() };
+        assert_eq!(neighbour.verify_detailed(b"c", tree.root()), Err(MerkleError::DirectionMismatch { step: 0 }));
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_bytes(&neighbour.to_bytes().unwrap()), Err(ProofFormatError::InvalidShape(_))));
+        assert!(matches!(MerkleProof::<Sha256Hasher>::from_json(&relabelled.to_json()), Err(ProofFormatError::InvalidShape(_))));
+
+        // Path of the promoted last leaf, claimed under another policy
+        let promoted = MerkleProof::from_tree(&tree, 4).unwrap();
+        assert!(promoted.verify(b"e", tree.root()));
+        let duplicated = MerkleProof { odd_policy: OddNodePolicy::DuplicateLast, ..promoted };
+        assert!(!duplicated.verify(b"e", tree.root()));
+    }
+}
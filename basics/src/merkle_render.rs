@@ -0,0 +1,194 @@
+use std::fmt::Write;
+
+use crate::merkle_hasher::MerkleHasher;
+use crate::merkle_tree::MerkleTree;
+
+/// Hex characters shown for each hash
+pub const HASH_PREFIX_LEN: usize = 8;
+
+//Fill colors: the leaf and its ancestors, then the siblings of its proof
+const PATH_COLOR: &str = "#f96";
+const SIBLING_COLOR: &str = "#9cf";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Dot,
+    Mermaid,
+}
+
+//Role of a node in the highlighted authentication path
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    None,
+    Path,
+    Sibling,
+}
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /// Render the tree as a Graphviz DOT graph, leaves at the bottom
+    pub fn to_dot(&self) -> String {
+        self.render(Format::Dot, None)
+    }
+
+    /// Same as `to_dot`, highlighting the authentication path of leaf `leaf_index`
+    ///
+    /// The leaf and its ancestors are filled orange, the siblings of `proof_path` blue.
+    /// None if the leaf doesn't exist or the tree isn't binary.
+    pub fn to_dot_with_path(&self, leaf_index: usize) -> Option<String> {
+        self.proof_path(leaf_index)?;
+        Some(self.render(Format::Dot, Some(leaf_index)))
+    }
+
+    /// Render the tree as a Mermaid flowchart, like the diagram of the README
+    pub fn to_mermaid(&self) -> String {
+        self.render(Format::Mermaid, None)
+    }
+
+    /// Same as `to_mermaid`, highlighting the authentication path of leaf `leaf_index`
+    pub fn to_mermaid_with_path(&self, leaf_index: usize) -> Option<String> {
+        self.proof_path(leaf_index)?;
+        Some(self.render(Format::Mermaid, Some(leaf_index)))
+    }
+
+    //Marks of every node, layer by layer
+    fn marks(&self, highlight: Option<usize>) -> Vec<Vec<Mark>> {
+        let mut marks: Vec<Vec<Mark>> = (0..=self.depth()).map(|level| vec![Mark::None; self.layer(level).unwrap().len()]).collect();
+        let Some(mut curr_idx) = highlight else { return marks; };
+        for layer in &mut marks {
+            layer[curr_idx] = Mark::Path;
+            //same siblings as proof_path: an unpaired node has none to show
+            if let Some(sibling) = layer.get_mut(curr_idx ^ 1) { *sibling = Mark::Sibling }
+            curr_idx /= 2;
+        }
+        marks
+    }
+
+    fn render(&self, format: Format, highlight: Option<usize>) -> String {
+        let marks = self.marks(highlight);
+        let mut out = String::new();
+        match format {
+            Format::Dot => out.push_str("digraph merkle {\n    rankdir=BT;\n    node [shape=box, fontname=monospace];\n"),
+            Format::Mermaid => out.push_str("graph BT\n"),
+        }
+
+        for (level, layer_marks) in marks.iter().enumerate() {
+            for (idx, hash) in self.layer(level).unwrap().iter().enumerate() {
+                let id = node_id(level, idx);
+                let short = &hex::encode(hash)[..HASH_PREFIX_LEN.min(2 * H::DIGEST_SIZE)];
+                let name = self.node_name(level, idx);
+                match format {
+                    Format::Dot => {
+                        let style = match layer_marks[idx] {
+                            Mark::None if name == "pad" => ", style=dashed".to_string(),
+                            Mark::None => String::new(),
+                            Mark::Path => format!(", style=filled, fillcolor=\"{PATH_COLOR}\""),
+                            Mark::Sibling => format!(", style=filled, fillcolor=\"{SIBLING_COLOR}\""),
+                        };
+                        writeln!(out, "    {id} [label=\"{name}\\n{short}\"{style}];").unwrap();
+                    }
+                    Format::Mermaid => writeln!(out, "    {id}[\"{name}<br/>{short}\"]").unwrap(),
+                }
+            }
+        }
+
+        //child -> parent, the last group of an odd layer has fewer edges
+        for level in 0..self.depth() {
+            for idx in 0..self.layer(level).unwrap().len() {
+                let (child, parent) = (node_id(level, idx), node_id(level + 1, idx / self.arity()));
+                match format {
+                    Format::Dot => writeln!(out, "    {child} -> {parent};").unwrap(),
+                    Format::Mermaid => writeln!(out, "    {child} --> {parent}").unwrap(),
+                }
+            }
+        }
+
+        match format {
+            Format::Dot => out.push_str("}\n"),
+            Format::Mermaid if highlight.is_some() => {
+                writeln!(out, "    classDef path fill:{PATH_COLOR},stroke:#333,stroke-width:4px").unwrap();
+                writeln!(out, "    classDef sibling fill:{SIBLING_COLOR},stroke:#333").unwrap();
+                for (class, mark) in [("path", Mark::Path), ("sibling", Mark::Sibling)] {
+                    let ids: Vec<String> = marks.iter()
+                        .enumerate()
+                        .flat_map(|(level, layer)| layer.iter().enumerate().filter(move |(_, m)| **m == mark).map(move |(idx, _)| node_id(level, idx)))
+                        .collect();
+                    if !ids.is_empty() { writeln!(out, "    class {} {class}", ids.join(",")).unwrap() }
+                }
+            }
+            Format::Mermaid => {}
+        }
+        out
+    }
+
+    //"root", "leaf i", "pad" for padding leaves, "L{level} #{index}" otherwise
+    fn node_name(&self, level: usize, idx: usize) -> String {
+        match level {
+            0 if idx >= self.num_leaves() => "pad".to_string(),
+            0 => format!("leaf {idx}"),
+            level if level == self.depth() => "root".to_string(),
+            level => format!("L{level} #{idx}"),
+        }
+    }
+}
+
+fn node_id(level: usize, idx: usize) -> String {
+    format!("n{level}_{idx}")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle_tree::{MerkleTree, OddNodePolicy};
+
+    fn tree(size: usize, odd_policy: OddNodePolicy) -> MerkleTree {
+        let data: Vec<Vec<u8>> = (0..size).map(|i| format!("item_{}", i).into_bytes()).collect();
+        MerkleTree::with_policy(data.iter().map(|d| d.as_slice()).collect(), odd_policy)
+    }
+
+    #[test]
+    fn test_dot_has_every_node_and_edge() {
+        let tree = tree(4, OddNodePolicy::Promote);
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph merkle {"));
+        assert!(dot.trim_end().ends_with('}'));
+        assert_eq!(dot.matches("[label=").count(), 7);
+        assert_eq!(dot.matches(" -> ").count(), 6);
+        let root = hex::encode(tree.root());
+        assert!(dot.contains(&format!("n2_0 [label=\"root\\n{}\"];", &root[..8])));
+        assert!(!dot.contains(&root), "hashes are truncated");
+    }
+
+    #[test]
+    fn test_highlighted_path_matches_proof_path() {
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let tree = tree(5, odd_policy.clone());
+            for idx in 0..5 {
+                let dot = tree.to_dot_with_path(idx).unwrap();
+                assert_eq!(dot.matches("fillcolor=\"#f96\"").count(), tree.depth() + 1, "{odd_policy:?}");
+                // One sibling per proof step, except the duplicated ones: the path node itself
+                let path_nodes: Vec<_> = (0..=tree.depth()).map(|level| tree.node(level, idx >> level).unwrap()).collect();
+                let siblings = tree.proof_path(idx).unwrap().iter().filter(|(hash, _)| !path_nodes.contains(hash)).count();
+                assert_eq!(dot.matches("fillcolor=\"#9cf\"").count(), siblings, "{odd_policy:?}, leaf {idx}");
+            }
+            assert!(tree.to_dot_with_path(5).is_none());
+        }
+        let dot = tree(4, OddNodePolicy::Promote).to_dot_with_path(2).unwrap();
+        for (id, color) in [("n0_2", "#f96"), ("n0_3", "#9cf"), ("n1_1", "#f96"), ("n1_0", "#9cf"), ("n2_0", "#f96")] {
+            assert!(dot.lines().any(|line| line.contains(&format!("{id} [")) && line.contains(color)), "{id}");
+        }
+    }
+
+    #[test]
+    fn test_mermaid() {
+        let tree = tree(3, OddNodePolicy::PadWith(b"pad".to_vec()));
+        let mermaid = tree.to_mermaid();
+        assert!(mermaid.starts_with("graph BT\n"));
+        assert!(mermaid.contains("n0_3[\"pad<br/>"));
+        assert_eq!(mermaid.matches(" --> ").count(), 6);
+        assert!(!mermaid.contains("classDef"));
+
+        let highlighted = tree.to_mermaid_with_path(0).unwrap();
+        assert!(highlighted.contains("    class n0_0,n1_0,n2_0 path\n"));
+        assert!(highlighted.contains("    class n0_1,n1_1 sibling\n"));
+        assert!(tree.to_mermaid_with_path(3).is_none());
+    }
+}
@@ -0,0 +1,584 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_proof::{domain_from_id, domain_id, policy_from_id, policy_id};
+use crate::merkle_tree::{layer_offsets, lift_unpaired, padded_width, HashDomain, MerkleNode, MerkleTree, OddNodePolicy, SiblingDirection, TreeConfig};
+
+/// First bytes of every tree file
+pub const MAGIC: [u8; 8] = *b"MERKTREE";
+/// Current version of the tree file format
+///
+/// File layout (version 1, integers little-endian):
+/// `magic | version: u8 | hash id: u8 | domain: u8 | policy: u8 | leaf count: u64 |
+/// pad length: u32 | pad leaf | root | checksum | nodes`
+///
+/// The checksum is the hash of every header byte before it. The nodes follow the
+/// in-memory layout: every layer back to back, leaves first, root last.
+pub const STORE_VERSION: u8 = 1;
+
+//(sibling, direction) pairs, as returned by `MerkleTree::proof_path`
+type SiblingPath<D> = Vec<(D, SiblingDirection)>;
+
+//magic + version + hash id + domain + policy + leaf count + pad length
+const FIXED_HEADER_LEN: usize = 8 + 1 + 1 + 1 + 1 + 8 + 4;
+
+/// Why a tree file was rejected
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    /// The file doesn't start with `MAGIC`
+    NotATreeFile,
+    UnsupportedVersion(u8),
+    /// The tree was saved with another hash function than the one expected
+    HashMismatch { expected: u8, found: u8 },
+    UnknownDomain(u8),
+    UnknownPolicy(u8),
+    /// The header doesn't hash to its stored checksum
+    ChecksumMismatch,
+    /// The file is truncated, or too long for the announced tree
+    InvalidLength { expected: u64, found: u64 },
+    /// A stored node isn't the hash of its children (or the stored root)
+    CorruptedNode { level: usize, index: usize },
+    /// An update targets a leaf the tree doesn't have
+    IndexOutOfRange { index: usize, num_leaves: usize },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "I/O error: {e}"),
+            StoreError::NotATreeFile => write!(f, "not a Merkle tree file"),
+            StoreError::UnsupportedVersion(v) => write!(f, "unsupported tree file version {v}"),
+            StoreError::HashMismatch { expected, found } => write!(f, "tree uses hash {found}, expected {expected}"),
+            StoreError::UnknownDomain(d) => write!(f, "unknown hash domain {d}"),
+            StoreError::UnknownPolicy(p) => write!(f, "unknown odd-node policy {p}"),
+            StoreError::ChecksumMismatch => write!(f, "header checksum mismatch"),
+            StoreError::InvalidLength { expected, found } => write!(f, "expected {expected} bytes, found {found}"),
+            StoreError::CorruptedNode { level, index } => write!(f, "node {index} of level {level} is corrupted"),
+            StoreError::IndexOutOfRange { index, num_leaves } => write!(f, "leaf {index} out of range for {num_leaves} leaves"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /// Write the tree to `path`, replacing any previous file atomically
+    ///
+    /// The tree is written to a temporary file next to `path`, synced to disk, then
+    /// renamed over `path`: after a crash `path` holds the old tree or the new one, never a mix.
+    /// Each save has its own temporary file, so concurrent saves to one path don't mix either.
+    /// To change a few leaves of a large stored tree, see `StoredMerkleTree::update_leaf`.
+    /// The file format only holds binary trees: other arities are an `InvalidInput` error.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if self.arity() != 2 { return Err(io::Error::new(io::ErrorKind::InvalidInput, "only binary trees can be saved")); }
+        let path = path.as_ref();
+        let tmp = tmp_path(path);
+        let (nodes, config) = self.raw_parts();
+        let mut out = BufWriter::new(OpenOptions::new().write(true).create_new(true).open(&tmp)?);
+        out.write_all(&header::<H>(config, self.num_leaves(), self.root()))?;
+        for node in nodes {
+            out.write_all(node.as_ref())?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, path)?;
+        sync_parent_dir(path)
+    }
+
+    /// Read back a tree written by `save`
+    ///
+    /// Every node is checked against its children and the root against the header,
+    /// so any corrupted byte is reported instead of producing wrong proofs.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        StoredMerkleTree::<H>::open(path)?.load()
+    }
+}
+
+/// A tree file opened without reading its nodes
+///
+/// Only the header is read by `open`; nodes and proofs are read from disk on demand,
+/// so a proof costs `depth()` small reads whatever the size of the tree.
+pub struct StoredMerkleTree<H: MerkleHasher = Sha256Hasher> {
+    file: File,
+    config: TreeConfig,
+    leaf_count: usize,
+    root: H::Digest,
+    //position of the first node in the file
+    data_start: u64,
+    //offsets[l] = index of the first node of level l, plus an end marker
+    offsets: Vec<usize>,
+}
+
+impl<H: MerkleHasher> StoredMerkleTree<H> {
+    /// Open a tree file, checking its header and its length
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::from_file(File::open(path)?)
+    }
+
+    /// Same as `open`, with write access for `update_leaf`
+    pub fn open_rw(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        Self::from_file(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    fn from_file(mut file: File) -> Result<Self, StoreError> {
+        let mut fixed = [0u8; FIXED_HEADER_LEN];
+        read_header_part(&mut file, &mut fixed)?;
+        if fixed[..8] != MAGIC { return Err(StoreError::NotATreeFile); }
+        if fixed[8] != STORE_VERSION { return Err(StoreError::UnsupportedVersion(fixed[8])); }
+        if fixed[9] != H::ID { return Err(StoreError::HashMismatch { expected: H::ID, found: fixed[9] }); }
+        let domain = domain_from_id(fixed[10]).ok_or(StoreError::UnknownDomain(fixed[10]))?;
+        let leaf_count = u64::from_le_bytes(fixed[12..20].try_into().unwrap());
+        let pad_len = u32::from_le_bytes(fixed[20..24].try_into().unwrap()) as usize;
+
+        //pad leaf, root and checksum
+        let file_len = file.metadata()?.len();
+        let rest_len = pad_len + 2 * H::DIGEST_SIZE;
+        if (FIXED_HEADER_LEN + rest_len) as u64 > file_len {
+            return Err(StoreError::InvalidLength { expected: (FIXED_HEADER_LEN + rest_len) as u64, found: file_len });
+        }
+        let mut rest = vec![0u8; rest_len];
+        read_header_part(&mut file, &mut rest)?;
+        let (pad, digests) = rest.split_at(pad_len);
+        let (root, checksum) = digests.split_at(H::DIGEST_SIZE);
+        if H::hash(&[&fixed, pad, root]).as_ref() != checksum { return Err(StoreError::ChecksumMismatch); }
+
+        let odd_policy = policy_from_id(fixed[11], pad).ok_or(StoreError::UnknownPolicy(fixed[11]))?;
+        let config = TreeConfig { odd_policy, domain, arity: 2 };
+        //`save` never writes an empty tree
+        let leaf_count = match usize::try_from(leaf_count) {
+            Ok(0) | Err(_) => return Err(StoreError::NotATreeFile),
+            Ok(count) => count,
+        };
+        let data_start = (FIXED_HEADER_LEN + rest_len) as u64;
+        //the checksum is not a MAC: a crafted leaf count must not overflow `layer_offsets`
+        let min_len = padded_width(leaf_count, &config)
+            .and_then(|width| (width as u64).checked_mul(H::DIGEST_SIZE as u64))
+            .and_then(|len| len.checked_add(data_start));
+        match min_len {
+            Some(min_len) if min_len <= file_len => {}
+            _ => return Err(StoreError::InvalidLength { expected: min_len.unwrap_or(u64::MAX), found: file_len }),
+        }
+        let offsets = layer_offsets(leaf_count, &config);
+
+        let expected = data_start + (*offsets.last().unwrap() * H::DIGEST_SIZE) as u64;
+        if file_len != expected { return Err(StoreError::InvalidLength { expected, found: file_len }); }
+        Ok(StoredMerkleTree { file, config, leaf_count, root: digest::<H>(root), data_start, offsets })
+    }
+
+    /// Return the root stored in the header
+    pub fn root(&self) -> H::Digest {
+        self.root
+    }
+
+    ///Return the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.offsets.len() - 2
+    }
+
+    /// Return the number of leaves (padding leaves excluded)
+    pub fn num_leaves(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// Return the policy used to complete odd layers
+    pub fn policy(&self) -> &OddNodePolicy {
+        &self.config.odd_policy
+    }
+
+    /// Return the hash domain of leaves and internal nodes
+    pub fn domain(&self) -> HashDomain {
+        self.config.domain
+    }
+
+    /// Read node `index` of level `level` from disk
+    pub fn node(&mut self, level: usize, index: usize) -> io::Result<Option<H::Digest>> {
+        if level > self.depth() || index >= self.width(level) { return Ok(None); }
+        self.read_node(self.offsets[level] + index).map(Some)
+    }
+
+    /// Same path as `MerkleTree::proof_path`, read from disk
+    pub fn proof_path(&mut self, leaf_index: usize) -> io::Result<Option<SiblingPath<H::Digest>>> {
+        if leaf_index >= self.num_leaves() { return Ok(None); }
+        let mut path = Vec::with_capacity(self.depth());
+        let mut curr_idx = leaf_index;
+        for level in 0..self.depth() {
+            let direction = if curr_idx % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+            if (curr_idx ^ 1) < self.width(level) {
+                path.push((self.read_node(self.offsets[level] + (curr_idx ^ 1))?, direction));
+            } else if self.config.odd_policy == OddNodePolicy::DuplicateLast {
+                //the unpaired node was hashed with itself
+                path.push((self.read_node(self.offsets[level] + curr_idx)?, direction));
+            } //else promoted: nothing to hash at this level
+            curr_idx /= 2;
+        }
+        Ok(Some(path))
+    }
+
+    /// Read every node into memory and check them all
+    pub fn load(mut self) -> Result<MerkleTree<H>, StoreError> {
+        self.file.seek(SeekFrom::Start(self.data_start))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut buf = vec![0u8; H::DIGEST_SIZE];
+        let mut nodes = Vec::with_capacity(*self.offsets.last().unwrap());
+        for _ in 0..*self.offsets.last().unwrap() {
+            reader.read_exact(&mut buf)?;
+            nodes.push(digest::<H>(&buf));
+        }
+
+        if let OddNodePolicy::PadWith(empty) = &self.config.odd_policy {
+            let pad = MerkleNode::<H>::leaf_with(empty, self.config.domain).hash;
+            if let Some(index) = (self.leaf_count..self.offsets[1]).find(|idx| nodes[*idx] != pad) {
+                return Err(StoreError::CorruptedNode { level: 0, index });
+            }
+        }
+        let depth = self.depth();
+        let tree = MerkleTree::from_raw_parts(nodes, self.config, self.leaf_count).expect("length checked by open");
+        if let Some((level, index)) = tree.first_bad_node() { return Err(StoreError::CorruptedNode { level, index }); }
+        if tree.root() != self.root { return Err(StoreError::CorruptedNode { level: depth, index: 0 }); }
+        Ok(tree)
+    }
+
+    /// Replace the data of leaf `leaf_index`, rewriting only its path up to the root
+    ///
+    /// The file must have been opened with `open_rw`. The new nodes are written and
+    /// synced first, then the header with the new root and its checksum, then synced
+    /// again. Unlike `save` this is not atomic: a crash in between leaves nodes that
+    /// don't match the header root, which `load` reports as `CorruptedNode`.
+    pub fn update_leaf(&mut self, leaf_index: usize, data: &[u8]) -> Result<(), StoreError> {
+        if leaf_index >= self.num_leaves() {
+            return Err(StoreError::IndexOutOfRange { index: leaf_index, num_leaves: self.num_leaves() });
+        }
+        let mut curr = MerkleNode::<H>::leaf_with(data, self.config.domain);
+        let mut path = vec![(leaf_index, curr.hash)];
+        let mut curr_idx = leaf_index;
+        for level in 0..self.depth() {
+            let sibling = curr_idx ^ 1;
+            curr = if sibling < self.width(level) {
+                let sibling = MerkleNode { hash: self.read_node(self.offsets[level] + sibling)? };
+                if curr_idx % 2 == 1 {
+                    MerkleNode::parent_with(&sibling, &curr, self.config.domain)
+                } else {
+                    MerkleNode::parent_with(&curr, &sibling, self.config.domain)
+                }
+            } else {
+                lift_unpaired(&curr, &self.config).expect("padded layers are never odd")
+            };
+            curr_idx /= 2;
+            path.push((self.offsets[level + 1] + curr_idx, curr.hash));
+        }
+
+        for (position, hash) in &path {
+            self.write_node(*position, hash)?;
+        }
+        self.file.sync_data()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header::<H>(&self.config, self.leaf_count, curr.hash))?;
+        self.file.sync_data()?;
+        self.root = curr.hash;
+        Ok(())
+    }
+
+    fn width(&self, level: usize) -> usize {
+        self.offsets[level + 1] - self.offsets[level]
+    }
+
+    fn write_node(&mut self, position: usize, hash: &H::Digest) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.data_start + (position * H::DIGEST_SIZE) as u64))?;
+        self.file.write_all(hash.as_ref())
+    }
+
+    fn read_node(&mut self, position: usize) -> io::Result<H::Digest> {
+        let mut buf = vec![0u8; H::DIGEST_SIZE];
+        self.file.seek(SeekFrom::Start(self.data_start + (position * H::DIGEST_SIZE) as u64))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(digest::<H>(&buf))
+    }
+}
+
+//Everything before the nodes, checksum included
+fn header<H: MerkleHasher>(config: &TreeConfig, leaf_count: usize, root: H::Digest) -> Vec<u8> {
+    let (policy, pad) = policy_id(&config.odd_policy);
+    let pad_len = u32::try_from(pad.len()).expect("pad leaves are at most u32::MAX bytes");
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&[STORE_VERSION, H::ID, domain_id(config.domain), policy]);
+    bytes.extend_from_slice(&(leaf_count as u64).to_le_bytes());
+    bytes.extend_from_slice(&pad_len.to_le_bytes());
+    bytes.extend_from_slice(pad);
+    bytes.extend_from_slice(root.as_ref());
+    let checksum = H::hash(&[&bytes]);
+    bytes.extend_from_slice(checksum.as_ref());
+    bytes
+}
+
+//A file shorter than its header is not a tree file
+fn read_header_part(file: &mut File, buf: &mut [u8]) -> Result<(), StoreError> {
+    file.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => StoreError::NotATreeFile,
+        _ => StoreError::Io(e),
+    })
+}
+
+fn digest<H: MerkleHasher>(bytes: &[u8]) -> H::Digest {
+    H::Digest::try_from(bytes).ok().expect("slice has the digest size")
+}
+
+//`tree.bin` is written as `tree.bin.<pid>.<n>.tmp` first, a new name for every save
+fn tmp_path(path: &Path) -> PathBuf {
+    static SAVES: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(format!(".{}.{}.tmp", process::id(), SAVES.fetch_add(1, Ordering::Relaxed)));
+    PathBuf::from(tmp)
+}
+
+//The rename is only durable once the directory entry itself is synced
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Keccak256Hasher;
+    use crate::merkle_tree::verify_proof_with;
+
+    // Unique per test and per process, removed on drop
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            TempPath(std::env::temp_dir().join(format!("basics_store_{}_{name}.bin", std::process::id())))
+        }
+    }
+
+    impl TempPath {
+        // Temporary files of `save` left next to the tree file
+        fn leftovers(&self) -> Vec<PathBuf> {
+            let prefix = format!("{}.", self.0.file_name().unwrap().to_str().unwrap());
+            fs::read_dir(self.0.parent().unwrap()).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.file_name().unwrap().to_str().is_some_and(|name| name.starts_with(&prefix) && name.ends_with(".tmp")))
+                .collect()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            for tmp in self.leftovers() {
+                let _ = fs::remove_file(tmp);
+            }
+        }
+    }
+
+    fn items(size: usize) -> Vec<Vec<u8>> {
+        (0..size).map(|i| format!("item_{}", i).into_bytes()).collect()
+    }
+
+    fn build<H: MerkleHasher>(size: usize, config: TreeConfig) -> MerkleTree<H> {
+        let data = items(size);
+        MerkleTree::build(data.iter().map(|d| d.as_slice()).collect(), config)
+    }
+
+    fn flip_byte(path: &Path, position: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[position as usize] ^= 0x01;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let file = TempPath::new("round_trip");
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"empty".to_vec())] {
+            for size in [1, 2, 5, 13] {
+                let config = TreeConfig { odd_policy: odd_policy.clone(), domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+                let tree = build::<Sha256Hasher>(size, config.clone());
+                tree.save(&file.0).unwrap();
+                let loaded = MerkleTree::<Sha256Hasher>::load(&file.0).unwrap();
+                assert_eq!(loaded.root(), tree.root());
+                assert_eq!(loaded.num_leaves(), size);
+                assert_eq!(loaded.policy(), &odd_policy);
+                assert_eq!(loaded.domain(), HashDomain::Rfc6962);
+                assert_eq!(loaded.proof_path(size - 1), tree.proof_path(size - 1));
+            }
+        }
+        assert!(file.leftovers().is_empty(), "the temporary file is renamed away");
+        let quad = build::<Sha256Hasher>(5, TreeConfig { arity: 4, ..TreeConfig::default() });
+        assert_eq!(quad.save(&file.0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_lazy_reads_match_memory() {
+        let file = TempPath::new("lazy");
+        let tree = build::<Keccak256Hasher>(21, TreeConfig { odd_policy: OddNodePolicy::DuplicateLast, ..TreeConfig::default() });
+        tree.save(&file.0).unwrap();
+
+        let mut stored = StoredMerkleTree::<Keccak256Hasher>::open(&file.0).unwrap();
+        assert_eq!(stored.root(), tree.root());
+        assert_eq!(stored.depth(), tree.depth());
+        assert_eq!(stored.node(1, 10).unwrap(), tree.node(1, 10));
+        assert_eq!(stored.node(1, 11).unwrap(), None);
+        for idx in 0..21 {
+            let path = stored.proof_path(idx).unwrap().unwrap();
+            assert_eq!(Some(path.clone()), tree.proof_path(idx));
+            assert!(verify_proof_with::<Keccak256Hasher>(format!("item_{idx}").as_bytes(), &path, stored.root(), HashDomain::Plain));
+        }
+        assert!(stored.proof_path(21).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_update_and_save_again() {
+        let file = TempPath::new("update");
+        build::<Sha256Hasher>(8, TreeConfig::default()).save(&file.0).unwrap();
+        let mut tree = MerkleTree::<Sha256Hasher>::load(&file.0).unwrap();
+        assert_eq!(tree.update_leaf(3, b"new"), Ok(()));
+        tree.save(&file.0).unwrap();
+        assert_eq!(MerkleTree::<Sha256Hasher>::load(&file.0).unwrap().root(), tree.root());
+    }
+
+    #[test]
+    fn test_interrupted_save_keeps_old_tree() {
+        let file = TempPath::new("interrupted");
+        let tree = build::<Sha256Hasher>(6, TreeConfig::default());
+        tree.save(&file.0).unwrap();
+        // A crash mid-save leaves a partial temporary file behind, never a partial `path`
+        let stale = tmp_path(&file.0);
+        fs::write(&stale, &MAGIC[..5]).unwrap();
+        assert_eq!(MerkleTree::<Sha256Hasher>::load(&file.0).unwrap().root(), tree.root());
+        tree.save(&file.0).unwrap();
+        assert_eq!(file.leftovers(), vec![stale]);
+    }
+
+    #[test]
+    fn test_concurrent_saves_dont_mix() {
+        let file = TempPath::new("concurrent");
+        let trees = [build::<Sha256Hasher>(300, TreeConfig::default()), build::<Sha256Hasher>(301, TreeConfig::default())];
+        std::thread::scope(|scope| {
+            for tree in &trees {
+                let path = &file.0;
+                scope.spawn(move || for _ in 0..20 { tree.save(path).unwrap() });
+            }
+        });
+        let root = MerkleTree::<Sha256Hasher>::load(&file.0).unwrap().root();
+        assert!(trees.iter().any(|tree| tree.root() == root));
+        assert!(file.leftovers().is_empty());
+    }
+
+    #[test]
+    fn test_update_in_place() {
+        let file = TempPath::new("update_in_place");
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"empty".to_vec())] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let mut tree = build::<Sha256Hasher>(11, config.clone());
+            tree.save(&file.0).unwrap();
+            let len = fs::metadata(&file.0).unwrap().len();
+
+            let mut stored = StoredMerkleTree::<Sha256Hasher>::open_rw(&file.0).unwrap();
+            for (idx, data) in [(10, b"last".as_slice()), (0, b"first"), (5, b"middle")] {
+                stored.update_leaf(idx, data).unwrap();
+                tree.update_leaf(idx, data).unwrap();
+                assert_eq!(stored.root(), tree.root(), "{config:?}, leaf {idx}");
+                assert_eq!(stored.proof_path(idx).unwrap(), tree.proof_path(idx));
+            }
+            assert_eq!(stored.update_leaf(11, b"x").err().map(|e| e.to_string()), Some("leaf 11 out of range for 11 leaves".to_string()));
+            drop(stored);
+            assert_eq!(fs::metadata(&file.0).unwrap().len(), len);
+            // Header checksum and every node still check out
+            assert_eq!(MerkleTree::<Sha256Hasher>::load(&file.0).unwrap().root(), tree.root());
+        }
+        let mut read_only = StoredMerkleTree::<Sha256Hasher>::open(&file.0).unwrap();
+        assert!(matches!(read_only.update_leaf(0, b"x"), Err(StoreError::Io(_))));
+    }
+
+    #[test]
+    fn test_corrupted_node_detected() {
+        let file = TempPath::new("corrupted_node");
+        let tree = build::<Sha256Hasher>(9, TreeConfig::default());
+        tree.save(&file.0).unwrap();
+        let data_start = header::<Sha256Hasher>(&TreeConfig::default(), 9, tree.root()).len() as u64;
+        // First byte of leaf 4
+        flip_byte(&file.0, data_start + 4 * 32);
+        assert!(matches!(MerkleTree::<Sha256Hasher>::load(&file.0), Err(StoreError::CorruptedNode { level: 1, index: 2 })));
+    }
+
+    #[test]
+    fn test_corrupted_root_detected() {
+        let file = TempPath::new("corrupted_root");
+        let tree = build::<Sha256Hasher>(4, TreeConfig::default());
+        tree.save(&file.0).unwrap();
+        let len = fs::metadata(&file.0).unwrap().len();
+        flip_byte(&file.0, len - 1);
+        assert!(matches!(MerkleTree::<Sha256Hasher>::load(&file.0), Err(StoreError::CorruptedNode { level: 2, index: 0 })));
+    }
+
+    #[test]
+    fn test_bad_headers_rejected() {
+        let file = TempPath::new("bad_header");
+        let tree = build::<Sha256Hasher>(3, TreeConfig::default());
+
+        tree.save(&file.0).unwrap();
+        flip_byte(&file.0, 12);
+        assert!(matches!(StoredMerkleTree::<Sha256Hasher>::open(&file.0), Err(StoreError::ChecksumMismatch)));
+
+        tree.save(&file.0).unwrap();
+        flip_byte(&file.0, 0);
+        assert!(matches!(StoredMerkleTree::<Sha256Hasher>::open(&file.0), Err(StoreError::NotATreeFile)));
+
+        tree.save(&file.0).unwrap();
+        assert!(matches!(StoredMerkleTree::<Keccak256Hasher>::open(&file.0), Err(StoreError::HashMismatch { expected: 3, found: 1 })));
+
+        let bytes = fs::read(&file.0).unwrap();
+        fs::write(&file.0, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(StoredMerkleTree::<Sha256Hasher>::open(&file.0), Err(StoreError::InvalidLength { .. })));
+        fs::write(&file.0, &bytes[..10]).unwrap();
+        assert!(matches!(StoredMerkleTree::<Sha256Hasher>::open(&file.0), Err(StoreError::NotATreeFile)));
+    }
+
+    #[test]
+    fn test_huge_leaf_count_rejected() {
+        let file = TempPath::new("huge_leaf_count");
+        let tree = build::<Sha256Hasher>(4, TreeConfig::default());
+        tree.save(&file.0).unwrap();
+        let nodes = &fs::read(&file.0).unwrap()[header::<Sha256Hasher>(&TreeConfig::default(), 4, tree.root()).len()..];
+
+        // A valid checksum over a leaf count the file can't hold
+        let pad = TreeConfig { odd_policy: OddNodePolicy::PadWith(vec![]), ..TreeConfig::default() };
+        for (config, leaf_count) in [(TreeConfig::default(), usize::MAX), (TreeConfig::default(), usize::MAX / 32), (pad, (1 << (usize::BITS - 1)) + 1)] {
+            let mut bytes = header::<Sha256Hasher>(&config, leaf_count, tree.root());
+            bytes.extend_from_slice(nodes);
+            fs::write(&file.0, bytes).unwrap();
+            assert!(matches!(StoredMerkleTree::<Sha256Hasher>::open(&file.0), Err(StoreError::InvalidLength { .. })), "{config:?}, {leaf_count}");
+            assert!(matches!(MerkleTree::<Sha256Hasher>::load(&file.0), Err(StoreError::InvalidLength { .. })));
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{self, Read};
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{lift_unpaired, MerkleNode, OddNodePolicy, TreeConfig};
+
+/// Merkle root computed on the fly, one leaf at a time
+///
+/// Only the left nodes still waiting for their right sibling are kept: one per
+/// bit of the leaf count, O(log n) memory. `finish` returns the same root as
+/// `MerkleTree::build` over the same leaves and config.
+pub struct StreamingRoot<H: MerkleHasher = Sha256Hasher> {
+    config: TreeConfig,
+    //pending[h] = completed node of height h waiting for its right sibling
+    pending: Vec<Option<MerkleNode<H>>>,
+    count: usize,
+}
+
+impl StreamingRoot {
+    /// SHA-256 stream with the default config
+    pub fn new() -> Self {
+        Self::build(TreeConfig::default())
+    }
+}
+
+impl Default for StreamingRoot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: MerkleHasher> StreamingRoot<H> {
+    /// Stream with any hasher and config
+    ///
+    /// Only binary trees are streamed: panics if `config.arity` isn't 2.
+    pub fn build(config: TreeConfig) -> Self {
+        if config.arity != 2 {panic!("The streaming root only supports binary trees!")}
+        StreamingRoot { config, pending: Vec::new(), count: 0 }
+    }
+
+    /// Add the next leaf
+    pub fn push(&mut self, leaf: &[u8]) {
+        let mut node = MerkleNode::leaf_with(leaf, self.config.domain);
+        let mut height = 0;
+        //like a binary counter: merge while the slot is taken
+        while let Some(left) = self.pending.get_mut(height).and_then(Option::take) {
+            node = MerkleNode::parent_with(&left, &node, self.config.domain);
+            height += 1;
+        }
+        if height == self.pending.len() { self.pending.push(None) }
+        self.pending[height] = Some(node);
+        self.count += 1;
+    }
+
+    /// Return the number of leaves pushed so far
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Complete the odd layers and return the root, None if no leaf was pushed
+    pub fn finish(self) -> Option<H::Digest> {
+        if self.count == 0 { return None; }
+        let domain = self.config.domain;
+        let mut width = match self.config.odd_policy {
+            OddNodePolicy::PadWith(_) => self.count.next_power_of_two(),
+            _ => self.count,
+        };
+        //padding subtree of the current height, only used by PadWith
+        let mut pad = match &self.config.odd_policy {
+            OddNodePolicy::PadWith(empty) => Some(MerkleNode::<H>::leaf_with(empty, domain)),
+            _ => None,
+        };
+
+        //carry = last node of the current layer, built from the tail of the leaves
+        let mut carry: Option<MerkleNode<H>> = None;
+        for height in 0.. {
+            let left = self.pending.get(height).copied().flatten();
+            let last = match (left, carry) {
+                (Some(left), Some(right)) => {
+                    carry = Some(MerkleNode::parent_with(&left, &right, domain));
+                    None
+                }
+                (Some(node), None) | (None, Some(node)) => Some(node),
+                (None, None) => None,
+            };
+            if let Some(node) = last {
+                if width == 1 { return Some(node.hash); }
+                //unpaired last node of the layer
+                carry = match pad {
+                    Some(pad) => Some(MerkleNode::parent_with(&node, &pad, domain)),
+                    None => lift_unpaired(&node, &self.config),
+                };
+            }
+            pad = pad.map(|pad| MerkleNode::parent_with(&pad, &pad, domain));
+            width = width.div_ceil(2);
+        }
+        unreachable!("the layer width reaches 1")
+    }
+}
+
+/// Root of the leaves yielded by `leaves`, None if there are none
+pub fn root_from_iter<H: MerkleHasher, I>(leaves: I, config: TreeConfig) -> Option<H::Digest>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut stream = StreamingRoot::<H>::build(config);
+    for leaf in leaves {
+        stream.push(leaf.as_ref());
+    }
+    stream.finish()
+}
+
+/// Root of `reader` split into `chunk_size`-byte leaves (the last one may be shorter)
+///
+/// Only one chunk is in memory at a time. Returns None for an empty input.
+pub fn root_from_reader<H: MerkleHasher, R: Read>(mut reader: R, chunk_size: usize, config: TreeConfig) -> io::Result<Option<H::Digest>> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk size must be positive"));
+    }
+    let mut stream = StreamingRoot::<H>::build(config);
+    let mut chunk = vec![0u8; chunk_size];
+    loop {
+        let filled = read_chunk(&mut reader, &mut chunk)?;
+        if filled == 0 { break; }
+        stream.push(&chunk[..filled]);
+        if filled < chunk_size { break; }
+    }
+    Ok(stream.finish())
+}
+
+//Fill `buf` unless the reader ends first, return how many bytes were read
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Blake2sHasher;
+    use crate::merkle_tree::{HashDomain, MerkleTree};
+
+    fn items(size: usize) -> Vec<Vec<u8>> {
+        (0..size).map(|i| format!("item_{}", i).into_bytes()).collect()
+    }
+
+    fn configs() -> Vec<TreeConfig> {
+        let mut configs = Vec::new();
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"pad".to_vec())] {
+            for domain in [HashDomain::Plain, HashDomain::Rfc6962] {
+                configs.push(TreeConfig { odd_policy: odd_policy.clone(), domain, ..TreeConfig::default() });
+            }
+        }
+        configs
+    }
+
+    #[test]
+    fn test_matches_merkle_tree() {
+        for size in 1..=40 {
+            let data = items(size);
+            for config in configs() {
+                let tree = MerkleTree::<Blake2sHasher>::build(data.iter().map(|d| d.as_slice()).collect(), config.clone());
+                let streamed = root_from_iter::<Blake2sHasher, _>(&data, config.clone());
+                assert_eq!(streamed, Some(tree.root()), "{config:?}, size {size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_stream() {
+        let stream = StreamingRoot::new();
+        assert!(stream.is_empty());
+        assert_eq!(stream.finish(), None);
+        assert_eq!(root_from_reader::<Sha256Hasher, _>(&b""[..], 4, TreeConfig::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_is_logarithmic() {
+        let mut stream = StreamingRoot::new();
+        for i in 0..10_000u32 {
+            stream.push(&i.to_le_bytes());
+        }
+        assert_eq!(stream.len(), 10_000);
+        // 10_000 < 2^14
+        assert!(stream.pending.len() <= 14);
+    }
+
+    #[test]
+    fn test_reader_chunks() {
+        let bytes: Vec<u8> = (0..=250u8).collect();
+        for chunk_size in [1, 7, 32, 251, 1000] {
+            let data: Vec<&[u8]> = bytes.chunks(chunk_size).collect();
+            let tree = MerkleTree::new(data);
+            let root = root_from_reader::<Sha256Hasher, _>(bytes.as_slice(), chunk_size, TreeConfig::default()).unwrap();
+            assert_eq!(root, Some(tree.root()), "chunk size {chunk_size}");
+        }
+        assert!(root_from_reader::<Sha256Hasher, _>(bytes.as_slice(), 0, TreeConfig::default()).is_err());
+    }
+
+    // Returns at most 3 bytes per read, like a socket
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(3).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_short_reads() {
+        let bytes = vec![42u8; 100];
+        let expected = root_from_reader::<Sha256Hasher, _>(bytes.as_slice(), 16, TreeConfig::default()).unwrap();
+        let trickled = root_from_reader::<Sha256Hasher, _>(Trickle(&bytes), 16, TreeConfig::default()).unwrap();
+        assert_eq!(trickled, expected);
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{layer_offsets, SiblingDirection, TreeConfig, LEAF_PREFIX, NODE_PREFIX};
+
+/// A node of a sum tree: its hash and the total balance beneath it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SumNode<D> {
+    pub hash: D,
+    pub sum: i64,
+}
+
+impl<D: AsRef<[u8]>> SumNode<D> {
+    /// Leaf of one account: `H(LEAF_PREFIX || data || balance)`, balance big-endian
+    pub fn leaf<H: MerkleHasher<Digest = D>>(data: &[u8], balance: i64) -> Self {
+        SumNode { hash: H::hash(&[&[LEAF_PREFIX], data, &balance.to_be_bytes()]), sum: balance }
+    }
+
+    /// Parent committing to both children and both sums, None if the sum overflows
+    ///
+    /// `H(NODE_PREFIX || left.hash || left.sum || right.hash || right.sum)`
+    pub fn parent<H: MerkleHasher<Digest = D>>(left: &Self, right: &Self) -> Option<Self> {
+        let sum = left.sum.checked_add(right.sum)?;
+        let hash = H::hash(&[
+            &[NODE_PREFIX],
+            left.hash.as_ref(), &left.sum.to_be_bytes(),
+            right.hash.as_ref(), &right.sum.to_be_bytes(),
+        ]);
+        Some(SumNode { hash, sum })
+    }
+}
+
+/// Sibling of one proof step, with the side it sits on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SumProofStep<D> {
+    pub sibling: SumNode<D>,
+    pub direction: SiblingDirection,
+}
+
+/// Why a sum tree could not be built
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SumTreeError {
+    /// A tree needs at least one account
+    EmptyInput,
+    /// Account `index` has a negative balance
+    NegativeBalance { index: usize },
+    /// The total doesn't fit in an i64
+    Overflow,
+}
+
+impl fmt::Display for SumTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SumTreeError::EmptyInput => write!(f, "the tree needs at least one account"),
+            SumTreeError::NegativeBalance { index } => write!(f, "account {index} has a negative balance"),
+            SumTreeError::Overflow => write!(f, "the total balance overflows"),
+        }
+    }
+}
+
+impl std::error::Error for SumTreeError {}
+
+/// Merkle sum tree for proofs of reserves (Maxwell)
+///
+/// Each node commits to the sum of the balances beneath it, so the root holds the
+/// published total and each account can check that its balance was counted in it.
+/// The leaves are padded up to a power of two with empty accounts (`b""`, balance 0).
+/// Negative balances are refused: they would let an exchange hide liabilities.
+pub struct MerkleSumTree<H: MerkleHasher = Sha256Hasher> {
+    //every layer back to back, as in MerkleTree: leaves (padding included) first, root last
+    nodes: Vec<SumNode<H::Digest>>,
+    //offsets[l] = position of level l in `nodes`, plus an end marker
+    offsets: Vec<usize>,
+    leaf_count: usize,
+}
+
+impl MerkleSumTree {
+    /// Build a SHA-256 sum tree over `(account data, balance)` pairs
+    pub fn new(accounts: &[(&[u8], i64)]) -> Result<Self, SumTreeError> {
+        Self::build(accounts)
+    }
+}
+
+impl<H: MerkleHasher> MerkleSumTree<H> {
+    /// Build a sum tree with any hasher
+    pub fn build(accounts: &[(&[u8], i64)]) -> Result<Self, SumTreeError> {
+        if accounts.is_empty() { return Err(SumTreeError::EmptyInput); }
+        if let Some(index) = accounts.iter().position(|(_, balance)| *balance < 0) {
+            return Err(SumTreeError::NegativeBalance { index });
+        }
+        let width = accounts.len().checked_next_power_of_two().ok_or(SumTreeError::Overflow)?;
+        //a power of two never has odd layers, whatever the policy
+        let offsets = layer_offsets(width, &TreeConfig::default());
+        let mut nodes = Vec::with_capacity(*offsets.last().unwrap());
+        nodes.extend(accounts.iter().map(|(data, balance)| SumNode::leaf::<H>(data, *balance)));
+        nodes.resize(width, SumNode::leaf::<H>(b"", 0));
+
+        for bounds in offsets.windows(3) {
+            for left in (bounds[0]..bounds[1]).step_by(2) {
+                let parent = SumNode::parent::<H>(&nodes[left], &nodes[left + 1]).ok_or(SumTreeError::Overflow)?;
+                nodes.push(parent);
+            }
+        }
+        Ok(MerkleSumTree { nodes, offsets, leaf_count: accounts.len() })
+    }
+
+    /// Return the root: the published commitment and total
+    pub fn root(&self) -> SumNode<H::Digest> {
+        *self.nodes.last().unwrap()
+    }
+
+    /// Return the sum of all balances
+    pub fn total(&self) -> i64 {
+        self.root().sum
+    }
+
+    ///Return the depth of the tree
+    pub fn depth(&self) -> usize {
+        self.offsets.len() - 2
+    }
+
+    /// Return the number of accounts (padding leaves excluded)
+    pub fn num_leaves(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// Inclusion proof of account `leaf_index`, `depth()` steps long
+    pub fn proof(&self, leaf_index: usize) -> Option<Vec<SumProofStep<H::Digest>>> {
+        if leaf_index >= self.num_leaves() { return None; }
+        let mut curr_idx = leaf_index;
+        let proof = self.offsets[..self.depth()].iter()
+            .map(|start| {
+                let direction = if curr_idx % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+                let step = SumProofStep { sibling: self.nodes[start + (curr_idx ^ 1)], direction };
+                curr_idx /= 2;
+                step
+            })
+            .collect();
+        Some(proof)
+    }
+}
+
+/// Check that an account with `balance` was counted in `root`
+///
+/// Every balance on the path must be non-negative and no sum may overflow,
+/// otherwise a sibling could cancel out part of the total.
+pub fn verify_sum_proof<H: MerkleHasher>(data: &[u8], balance: i64, proof: &[SumProofStep<H::Digest>], root: &SumNode<H::Digest>) -> bool {
+    if balance < 0 { return false; }
+    let mut curr = SumNode::leaf::<H>(data, balance);
+    for step in proof {
+        if step.sibling.sum < 0 { return false; }
+        let parent = match step.direction {
+            SiblingDirection::Left => SumNode::parent::<H>(&step.sibling, &curr),
+            SiblingDirection::Right => SumNode::parent::<H>(&curr, &step.sibling),
+        };
+        match parent {
+            Some(parent) => curr = parent,
+            None => return false,
+        }
+    }
+    curr == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Blake2sHasher;
+
+    fn accounts(balances: &[i64]) -> Vec<(Vec<u8>, i64)> {
+        balances.iter().enumerate().map(|(i, balance)| (format!("user_{}", i).into_bytes(), *balance)).collect()
+    }
+
+    fn as_refs(accounts: &[(Vec<u8>, i64)]) -> Vec<(&[u8], i64)> {
+        accounts.iter().map(|(data, balance)| (data.as_slice(), *balance)).collect()
+    }
+
+    #[test]
+    fn test_root_holds_the_total() {
+        let accounts = accounts(&[10, 0, 250, 7, 3]);
+        let tree = MerkleSumTree::new(&as_refs(&accounts)).unwrap();
+        assert_eq!(tree.total(), 270);
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.num_leaves(), 5);
+        // 8 leaves, padding included, then 4 + 2 + 1 parents in one array
+        assert_eq!(tree.nodes.len(), 15);
+        assert_eq!(tree.offsets, vec![0, 8, 12, 14, 15]);
+    }
+
+    #[test]
+    fn test_proofs_verify() {
+        for size in 1..=9 {
+            let accounts = accounts(&(0..size).map(|i| 100 * i + 1).collect::<Vec<_>>());
+            let tree = MerkleSumTree::<Blake2sHasher>::build(&as_refs(&accounts)).unwrap();
+            for (idx, (data, balance)) in accounts.iter().enumerate() {
+                let proof = tree.proof(idx).unwrap();
+                assert!(verify_sum_proof::<Blake2sHasher>(data, *balance, &proof, &tree.root()), "size {size}, leaf {idx}");
+                // Same account, balance not the one counted
+                assert!(!verify_sum_proof::<Blake2sHasher>(data, balance - 1, &proof, &tree.root()));
+            }
+            assert!(tree.proof(size as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn test_total_is_committed() {
+        let accounts = accounts(&[5, 6, 7, 8]);
+        let tree = MerkleSumTree::new(&as_refs(&accounts)).unwrap();
+        let proof = tree.proof(1).unwrap();
+        // Publishing a smaller total with the same hash
+        let understated = SumNode { sum: tree.total() - 8, ..tree.root() };
+        assert!(!verify_sum_proof::<Sha256Hasher>(b"user_1", 6, &proof, &understated));
+
+        // Moving balance from a sibling into the total it hides
+        let mut shifted = proof.clone();
+        shifted[0].sibling.sum -= 5;
+        assert!(!verify_sum_proof::<Sha256Hasher>(b"user_1", 6, &shifted, &tree.root()));
+    }
+
+    #[test]
+    fn test_negative_balances_rejected() {
+        let accounts = accounts(&[5, -3, 7]);
+        assert_eq!(MerkleSumTree::new(&as_refs(&accounts)).err(), Some(SumTreeError::NegativeBalance { index: 1 }));
+
+        // A forged sibling with a negative sum cancelling out the user's balance
+        let user = SumNode::leaf::<Sha256Hasher>(b"user_0", 5);
+        let negative = SumNode::leaf::<Sha256Hasher>(b"liability", -5);
+        let root = SumNode::parent::<Sha256Hasher>(&user, &negative).unwrap();
+        assert_eq!(root.sum, 0);
+        let proof = [SumProofStep { sibling: negative, direction: SiblingDirection::Right }];
+        assert!(!verify_sum_proof::<Sha256Hasher>(b"user_0", 5, &proof, &root));
+        assert!(!verify_sum_proof::<Sha256Hasher>(b"user_0", -5, &[], &root));
+    }
+
+    #[test]
+    fn test_overflow_and_empty() {
+        let accounts = accounts(&[i64::MAX, 1]);
+        assert_eq!(MerkleSumTree::new(&as_refs(&accounts)).err(), Some(SumTreeError::Overflow));
+        assert_eq!(MerkleSumTree::new(&[]).err(), Some(SumTreeError::EmptyInput));
+
+        let big = SumNode::leaf::<Sha256Hasher>(b"big", i64::MAX);
+        let proof = [SumProofStep { sibling: big, direction: SiblingDirection::Left }];
+        assert!(!verify_sum_proof::<Sha256Hasher>(b"user", 1, &proof, &big));
+    }
+}
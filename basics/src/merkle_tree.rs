@@ -1,123 +1,930 @@
-use sha2::{Sha256, Digest};
+use std::fmt;
+use std::ops::Range;
+use std::thread;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+
+/// Digest of the default hasher (SHA-256)
 pub type Hash = [u8;32];
 /// A single node in the Merkle tree
-#[derive(Clone, Debug)]
-pub struct MerkleNode {
-    pub hash: [u8; 32],
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MerkleNode<H: MerkleHasher = Sha256Hasher> {
+    pub hash: H::Digest,
 }
 
+//SHA-256 shortcuts, `MerkleNode::leaf(..)` stays usable without naming a hasher
 impl MerkleNode {
     /// Create a leaf node by hashing data
     pub fn leaf(data: &[u8]) -> Self {
-        // TODO: Hash the data with SHA-256, store the 32-byte result
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
-        MerkleNode { hash: result.into() } //into instead of try_into because of fixed 32bytes size
+        Self::leaf_with(data, HashDomain::Plain)
     }
 
     /// Create an internal node by hashing two children
     /// The order matters: hash(left || right), not (right || left)
     pub fn parent(left: &MerkleNode, right: &MerkleNode) -> Self {
-        // TODO: Hash the concatenation of left.hash and right.hash
-        let mut hasher = Sha256::new();
-        hasher.update(&left.hash);  //& to prevent useless copy
-        hasher.update(&right.hash); //whithout & we make a useless copy, thats not idiomatic
-        let res = hasher.finalize();
-        MerkleNode { hash: res.into() }
+        Self::parent_with(left, right, HashDomain::Plain)
+    }
+}
+
+impl<H: MerkleHasher> MerkleNode<H> {
+    /// Create a leaf node, prefixing the data with `LEAF_PREFIX` under `HashDomain::Rfc6962`
+    pub fn leaf_with(data: &[u8], domain: HashDomain) -> Self {
+        let hash = match domain {
+            HashDomain::Plain => H::hash(&[data]),
+            HashDomain::Rfc6962 => H::hash(&[&[LEAF_PREFIX], data]),
+        };
+        MerkleNode { hash }
     }
+
+    /// Create an internal node, prefixing the children with `NODE_PREFIX` under `HashDomain::Rfc6962`
+    pub fn parent_with(left: &Self, right: &Self, domain: HashDomain) -> Self {
+        //order matters: left first
+        let (left, right) = (left.hash.as_ref(), right.hash.as_ref());
+        let hash = match domain {
+            HashDomain::Plain => H::hash(&[left, right]),
+            HashDomain::Rfc6962 => H::hash(&[&[NODE_PREFIX], left, right]),
+        };
+        MerkleNode { hash }
+    }
+
+    /// Create an internal node from any number of children, in order
+    ///
+    /// With two children this is `parent_with(left, right, domain)`.
+    pub fn from_children(children: &[Self], domain: HashDomain) -> Self {
+        let mut parts: Vec<&[u8]> = Vec::with_capacity(children.len() + 1);
+        if domain == HashDomain::Rfc6962 { parts.push(&[NODE_PREFIX]) }
+        parts.extend(children.iter().map(|child| child.hash.as_ref()));
+        MerkleNode { hash: H::hash(&parts) }
+    }
+}
+
+/// Prefix of a leaf preimage under `HashDomain::Rfc6962`
+pub const LEAF_PREFIX: u8 = 0x00;
+/// Prefix of an internal node preimage under `HashDomain::Rfc6962`
+pub const NODE_PREFIX: u8 = 0x01;
+
+/// How leaf and internal node preimages are told apart
+///
+/// With `Plain`, a 64-byte leaf equal to `left || right` hashes like an internal node,
+/// which lets a shortened proof pass verification (second-preimage attack).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashDomain {
+    /// Raw hash of the leaf data and of `left || right`
+    #[default]
+    Plain,
+    /// RFC 6962: `0x00 || data` for leaves, `0x01 || left || right` for nodes
+    Rfc6962,
 }
 
-/// A complete Merkle tree with power-of-2 leaves
-pub struct MerkleTree {
-    //store all layers
-    pub layers: Vec<Vec<MerkleNode>>,
+/// How a layer with an odd number of nodes gets completed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OddNodePolicy {
+    /// Bitcoin-style: the unpaired node is hashed with a copy of itself
+    DuplicateLast,
+    /// RFC 6962-style: the unpaired node moves up to the next layer unchanged
+    #[default]
+    Promote,
+    /// The leaf layer is padded up to the next power of two with this empty leaf
+    PadWith(Vec<u8>),
+}
+
+/// Everything that changes the shape or the hashes of a tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeConfig {
+    pub odd_policy: OddNodePolicy,
+    pub domain: HashDomain,
+    /// Children hashed by each internal node, at least 2
+    ///
+    /// With `arity` k, an odd layer ends with a group of fewer than k nodes: `DuplicateLast`
+    /// completes it with copies of its last node, `Promote` hashes the nodes it has (or moves
+    /// a single one up), `PadWith` pads the leaves up to a power of k.
+    pub arity: usize,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        TreeConfig { odd_policy: OddNodePolicy::default(), domain: HashDomain::default(), arity: 2 }
+    }
+}
+
+/// A complete Merkle tree over any number of leaves (>= 1)
+///
+/// Every hash lives in one contiguous array, layer after layer from the leaves
+/// up to the root. Node `i` of a level has its parent at `i / 2` one level up,
+/// its sibling at `i ^ 1` and its children at `2i` and `2i + 1` one level down
+/// (`i / k` and `ki..ki + k` with `TreeConfig::arity` k).
+///
+/// Proofs with a `SiblingDirection` per level (`proof_path`, multiproofs, range and
+/// consistency proofs) exist for binary trees only; `kary_proof` works for any arity.
+/// A heap layout (children of `i` at `2i + 1` and `2i + 2`) would need every level
+/// full: with `DuplicateLast` or `Promote` the widths are odd, so the levels are
+/// located by `offsets` instead and no slot is wasted on missing nodes.
+pub struct MerkleTree<H: MerkleHasher = Sha256Hasher> {
+    //store all layers, back to back: leaves first, root last
+    nodes: Vec<H::Digest>,
+    //offsets[l] = position of level l in `nodes`, plus an end marker
+    offsets: Vec<usize>,
+    //odd layers policy, hash domain and arity, proof_path needs them
+    config: TreeConfig,
+    //real leaves, without the padding of OddNodePolicy::PadWith
+    leaf_count: usize,
 }
 
 impl MerkleTree {
+    /// Build a tree with the default policy (`OddNodePolicy::Promote`)
+    ///
+    /// Panics if `data` is empty, see `try_new`.
     pub fn new(data: Vec<&[u8]>) -> Self {
-        //Validate that data.len() is a power of 2
-        if data.len() < 2 || !is_a_pow_of_two(data.len()) {panic!("The len of data must be a power of two!")} //logic negation
-        //our future merkle tree
-        let mut layers:Vec<Vec<MerkleNode>>= Vec::new();
-        //convert data into merklenode (hash) for layer 0
-        let mut convert: Vec<MerkleNode> = Vec::new();
-        for elm in data {
-            //layers.push(MerkleNode::leaf(elm))
-            convert.push(MerkleNode::leaf(elm))
-        } //data moved here
-        layers.push(convert);
+        Self::with_policy(data, OddNodePolicy::default())
+    }
+
+    /// Same as `new`, returning an error instead of panicking
+    pub fn try_new(data: Vec<&[u8]>) -> Result<Self, MerkleError> {
+        Self::try_build(data, TreeConfig::default())
+    }
+
+    /// Build a tree, completing odd layers according to `policy`
+    pub fn with_policy(data: Vec<&[u8]>, policy: OddNodePolicy) -> Self {
+        Self::with_config(data, TreeConfig { odd_policy: policy, ..TreeConfig::default() })
+    }
+
+    /// Build a tree with an explicit odd-node policy and hash domain
+    pub fn with_config(data: Vec<&[u8]>, config: TreeConfig) -> Self {
+        Self::build(data, config)
+    }
+}
+
+impl<H: MerkleHasher> MerkleTree<H> {
+    /// Build a tree with any hasher, e.g. `MerkleTree::<Keccak256Hasher>::build(data, config)`
+    pub fn build(data: Vec<&[u8]>, config: TreeConfig) -> Self {
+        Self::try_build(data, config).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Same as `build`, returning an error instead of panicking
+    pub fn try_build(data: Vec<&[u8]>, config: TreeConfig) -> Result<Self, MerkleError> {
+        let domain = config.domain;
+        //convert data into hashes for layer 0
+        let leaves = data.iter().map(|elm| MerkleNode::<H>::leaf_with(elm, domain).hash).collect();
+        Self::from_leaf_hashes(leaves, config)
+    }
+
+    /// Build a tree over already hashed leaves (`MerkleNode::leaf_with` of the data)
+    ///
+    /// Useful when the leaves were hashed elsewhere, e.g. streamed from a file.
+    pub fn from_leaf_hashes(leaves: Vec<H::Digest>, config: TreeConfig) -> Result<Self, MerkleError> {
+        let domain = config.domain;
+        check_leaf_count(leaves.len(), &config)?;
+        let leaf_count = leaves.len();
+        let offsets = layer_offsets(leaf_count, &config);
+        //our future merkle tree, allocated once
+        let mut nodes: Vec<H::Digest> = Vec::with_capacity(*offsets.last().unwrap());
+        nodes.extend(leaves);
+        if let OddNodePolicy::PadWith(empty) = &config.odd_policy {
+            //with a power of two leaves no layer is ever odd
+            nodes.resize(offsets[1], MerkleNode::<H>::leaf_with(empty, domain).hash);
+        }
 
         //Build layers bottom-up until until reach a single root
-        while layers.last().unwrap().len() > 1 { //licite her because of the push just before, so we know the vec isn't empty
-            //to store the next layer
-            let mut next_layer: Vec<MerkleNode>= Vec::new();
-            let part = layers.last().unwrap().chunks_exact(2);
-            for chunk in part{
-                let new_parent  = MerkleNode::parent(&chunk[0], &chunk[1]);
-                next_layer.push(new_parent)
+        for bounds in offsets.windows(3) {
+            let (start, end) = (bounds[0], bounds[1]);
+            for first in (start..end).step_by(config.arity) {
+                let parent = group_parent::<H>(&nodes[first..end.min(first + config.arity)], &config);
+                nodes.push(parent);
             }
-            layers.push(next_layer)
         }
 
+        Ok(MerkleTree { nodes, offsets, config, leaf_count })
+    }
+
+    /// Same tree as `build`, hashing the leaves and the large lower layers on `threads` threads
+    ///
+    /// `threads == 0` uses every available core. Layers are cut into chunks of a multiple of
+    /// the arity, so each thread hashes whole groups and the result is bit-identical to `build`.
+    pub fn build_parallel(data: Vec<&[u8]>, config: TreeConfig, threads: usize) -> Self {
+        let threads = match threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let domain = config.domain;
+        if let Err(e) = check_leaf_count(data.len(), &config) {panic!("{e}")}
+        let leaf_count = data.len();
+        let offsets = layer_offsets(leaf_count, &config);
 
-        MerkleTree { layers,}
+        let mut nodes = Vec::with_capacity(*offsets.last().unwrap());
+        nodes.extend(in_parallel(&data, threads, 1, |chunk| {
+            chunk.iter().map(|elm| MerkleNode::<H>::leaf_with(elm, domain).hash).collect()
+        }));
+        if let OddNodePolicy::PadWith(empty) = &config.odd_policy {
+            nodes.resize(offsets[1], MerkleNode::<H>::leaf_with(empty, domain).hash);
+        }
+        for bounds in offsets.windows(3) {
+            let next_layer = in_parallel(&nodes[bounds[0]..bounds[1]], threads, config.arity, |chunk| parent_layer::<H>(chunk, &config));
+            nodes.extend(next_layer);
+        }
+        MerkleTree { nodes, offsets, config, leaf_count }
     }
-    
 
 
     /// Return the root hash
-    pub fn root(&self) -> Hash {
-        self.layers.last().unwrap()[0].hash
+    pub fn root(&self) -> H::Digest {
+        *self.nodes.last().unwrap()
     }
 
     ///Return the depth of the tree
     pub fn depth(&self) -> usize {
-        self.layers.len() - 1
+        self.offsets.len() - 2
     }
 
-    /// Return the number of leaves
+    /// Return the number of leaves (padding leaves excluded)
     pub fn num_leaves(&self) -> usize {
-        self.layers[0].len()
+        self.leaf_count
+    }
+
+    /// Return the policy used to complete odd layers
+    pub fn policy(&self) -> &OddNodePolicy {
+        &self.config.odd_policy
+    }
+
+    /// Return the hash domain of leaves and internal nodes
+    pub fn domain(&self) -> HashDomain {
+        self.config.domain
+    }
+
+    /// Return the number of children per internal node
+    pub fn arity(&self) -> usize {
+        self.config.arity
+    }
+
+    /// Return the hashes of level `level` (0 = leaves, padding included, `depth()` = root)
+    pub fn layer(&self, level: usize) -> Option<&[H::Digest]> {
+        let (start, end) = (*self.offsets.get(level)?, *self.offsets.get(level + 1)?);
+        Some(&self.nodes[start..end])
+    }
+
+    /// Return node `index` of level `level`
+    pub fn node(&self, level: usize, index: usize) -> Option<H::Digest> {
+        self.layer(level)?.get(index).copied()
+    }
+
+    /// Return the leaf hashes (padding leaves excluded)
+    pub fn leaves(&self) -> &[H::Digest] {
+        &self.nodes[..self.leaf_count]
+    }
+
+    //Raw flat storage and config, for merkle_store
+    pub(crate) fn raw_parts(&self) -> (&[H::Digest], &TreeConfig) {
+        (&self.nodes, &self.config)
+    }
+
+    //First (level, index) that isn't the hash of its children, for merkle_store
+    pub(crate) fn first_bad_node(&self) -> Option<(usize, usize)> {
+        for level in 1..=self.depth() {
+            for (idx, hash) in self.layer(level).unwrap().iter().enumerate() {
+                if group_parent::<H>(self.children_of(level, idx), &self.config) != *hash {
+                    return Some((level, idx));
+                }
+            }
+        }
+        None
+    }
+
+    //Inverse of `raw_parts`, None if `nodes` doesn't have the size of such a tree
+    pub(crate) fn from_raw_parts(nodes: Vec<H::Digest>, config: TreeConfig, leaf_count: usize) -> Option<Self> {
+        if leaf_count == 0 { return None; }
+        let offsets = layer_offsets(leaf_count, &config);
+        if nodes.len() != *offsets.last().unwrap() { return None; }
+        Some(MerkleTree { nodes, offsets, config, leaf_count })
     }
 
     /// Returns
     /// A vector of (hash, direction) tuples where:
     /// - hash: the sibling hash at this level
     /// - direction: whether the sibling is on the left or right
-    pub fn proof_path(&self, leaf_index: usize) -> Option<Vec<(Hash, SiblingDirection)>> {
-        if leaf_index >= self.num_leaves() { return None; }
-        let mut path = Vec::new();
+    ///
+    /// With `OddNodePolicy::Promote` a promoted node has no sibling at that level,
+    /// so the path can be shorter than `depth()`. None for a tree that isn't binary.
+    pub fn proof_path(&self, leaf_index: usize) -> Option<Vec<(H::Digest, SiblingDirection)>> {
+        if leaf_index >= self.num_leaves() || self.arity() != 2 { return None; }
+        let mut path = Vec::with_capacity(self.depth());
         let mut curr_idx = leaf_index;
-        for i in 0..self.depth() {
-            if curr_idx%2 == 0 {path.push((self.layers[i][curr_idx+1].hash,SiblingDirection::Right))} else {
-                path.push((self.layers[i][curr_idx-1].hash,SiblingDirection::Left))
+        for level in 0..self.depth() {
+            let layer = self.layer(level).unwrap();
+            let direction = if curr_idx % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+            match layer.get(sibling(curr_idx)) {
+                Some(hash) => path.push((*hash, direction)),
+                //the unpaired node was hashed with itself
+                None if self.config.odd_policy == OddNodePolicy::DuplicateLast => path.push((layer[curr_idx], direction)),
+                None => {} //promoted: nothing to hash at this level
             }
-            curr_idx /= 2;
+            curr_idx = parent(curr_idx);
         }
         Some(path)
     }
+
+    /// Same as `proof_path`, with an error for an out-of-range index or a tree that isn't binary
+    pub fn try_proof_path(&self, leaf_index: usize) -> Result<Vec<(H::Digest, SiblingDirection)>, MerkleError> {
+        if self.arity() != 2 { return Err(MerkleError::NotBinary(self.arity())); }
+        self.proof_path(leaf_index).ok_or(MerkleError::IndexOutOfRange { index: leaf_index, num_leaves: self.num_leaves() })
+    }
+
+    /// Check a proof of leaf `leaf_index` against this tree and explain any failure
+    ///
+    /// Takes its arguments in the order of the free `verify_proof_detailed`.
+    /// Besides the checks of `verify_proof_detailed`, every recomputed node is compared
+    /// with the stored one, so `Diverged` gives the first level that went wrong
+    /// (0 if the leaf data itself is not the stored one).
+    pub fn verify_proof_detailed(&self, leaf: &[u8], leaf_index: usize, proof: &[(H::Digest, SiblingDirection)]) -> Result<(), MerkleError> {
+        let steps = check_shape(leaf_index, self.num_leaves(), proof, &self.config)?;
+        let mut curr = MerkleNode::<H>::leaf_with(leaf, self.config.domain);
+        let mut proof = proof.iter();
+        let mut curr_idx = leaf_index;
+        if self.node(0, curr_idx) != Some(curr.hash) { return Err(MerkleError::Diverged { level: 0 }); }
+        for (level, has_sibling) in steps.iter().enumerate() {
+            curr = if *has_sibling {
+                let (sib, direction) = proof.next().unwrap();
+                hash_step(curr, *sib, *direction, self.config.domain)
+            } else {
+                lift_unpaired(&curr, &self.config).expect("padded layers are never odd")
+            };
+            curr_idx = parent(curr_idx);
+            if self.node(level + 1, curr_idx) != Some(curr.hash) { return Err(MerkleError::Diverged { level: level + 1 }); }
+        }
+        Ok(())
+    }
+
+    /// Build one proof for several leaves at once
+    ///
+    /// Indices are sorted and deduplicated; siblings shared by several paths, or
+    /// recomputable from the proven leaves themselves, are sent only once.
+    /// Returns None if `leaf_indices` is empty or holds an out-of-range index, or if
+    /// the tree isn't binary.
+    pub fn multiproof(&self, leaf_indices: &[usize]) -> Option<MultiProof<H::Digest>> {
+        let mut indices = leaf_indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() || *indices.last().unwrap() >= self.num_leaves() || self.arity() != 2 { return None; }
+
+        let mut siblings = Vec::new();
+        let mut known = indices.clone();
+        for level in 0..self.depth() {
+            let layer = self.layer(level).unwrap();
+            let mut i = 0;
+            while i < known.len() {
+                let idx = known[i];
+                if idx % 2 == 1 {
+                    siblings.push(layer[sibling(idx)]);
+                } else if i + 1 < known.len() && known[i+1] == sibling(idx) {
+                    i += 1; //both children are known, nothing to send
+                } else if let Some(hash) = layer.get(sibling(idx)) {
+                    siblings.push(*hash);
+                } //else unpaired last node: duplicated or promoted, nothing to send
+                i += 1;
+            }
+            //parents of the known nodes are known one level up
+            known = known.iter().map(|idx| parent(*idx)).collect();
+            known.dedup();
+        }
+        Some(MultiProof { indices, siblings })
+    }
+
+    /// Prove the contiguous leaves `range` at once
+    ///
+    /// Inside the range every node is recomputed by the verifier, so only the
+    /// siblings on the left and right edges are sent: at most two per level.
+    /// Returns None if the range is empty or goes past `num_leaves()`, or if the tree isn't binary.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn range_proof(&self, range: Range<usize>) -> Option<RangeProof<H::Digest>> {
+        if range.is_empty() || range.end > self.num_leaves() || self.arity() != 2 { return None; }
+        let (mut left, mut right) = (Vec::new(), Vec::new());
+        let (mut lo, mut hi) = (range.start, range.end - 1);
+        for level in 0..self.depth() {
+            let layer = self.layer(level).unwrap();
+            if lo % 2 == 1 { left.push(layer[sibling(lo)]) }
+            if hi % 2 == 0 {
+                //else unpaired last node: duplicated or promoted, nothing to send
+                if let Some(hash) = layer.get(sibling(hi)) { right.push(*hash) }
+            }
+            (lo, hi) = (parent(lo), parent(hi));
+        }
+        Some(RangeProof { start: range.start, left, right })
+    }
+
+    /// Replace the data of leaf `leaf_index` and recompute its path up to the root
+    ///
+    /// Returns `IndexOutOfRange`, leaving the tree untouched, if the index is out of range.
+    pub fn update_leaf(&mut self, leaf_index: usize, data: &[u8]) -> Result<(), MerkleError> {
+        self.update_leaf_hash(leaf_index, MerkleNode::<H>::leaf_with(data, self.config.domain).hash)
+    }
+
+    /// Same as `update_leaf` with an already hashed leaf
+    pub fn update_leaf_hash(&mut self, leaf_index: usize, hash: H::Digest) -> Result<(), MerkleError> {
+        if leaf_index >= self.num_leaves() { return Err(MerkleError::IndexOutOfRange { index: leaf_index, num_leaves: self.num_leaves() }); }
+        self.nodes[leaf_index] = hash;
+        let mut curr_idx = leaf_index;
+        for level in 0..self.depth() {
+            curr_idx /= self.arity();
+            self.recompute(level + 1, curr_idx);
+        }
+        Ok(())
+    }
+
+    /// Replace several leaves, recomputing each shared ancestor only once
+    ///
+    /// If an index appears twice the last update wins. Returns `IndexOutOfRange` for
+    /// the first index out of range, leaving the tree untouched.
+    pub fn update_many(&mut self, updates: &[(usize, &[u8])]) -> Result<(), MerkleError> {
+        if let Some((index, _)) = updates.iter().find(|(idx, _)| *idx >= self.num_leaves()) {
+            return Err(MerkleError::IndexOutOfRange { index: *index, num_leaves: self.num_leaves() });
+        }
+        let mut dirty = Vec::with_capacity(updates.len());
+        for (idx, data) in updates {
+            self.nodes[*idx] = MerkleNode::<H>::leaf_with(data, self.config.domain).hash;
+            dirty.push(*idx);
+        }
+        dirty.sort_unstable();
+        for level in 0..self.depth() {
+            //siblings share their parent: dedup after dividing
+            dirty = dirty.iter().map(|idx| idx / self.arity()).collect();
+            dirty.dedup();
+            for idx in &dirty {
+                self.recompute(level + 1, *idx);
+            }
+        }
+        Ok(())
+    }
+
+    //Rehash node `idx` of layer `level` from its children
+    fn recompute(&mut self, level: usize, idx: usize) {
+        let parent = group_parent::<H>(self.children_of(level, idx), &self.config);
+        self.nodes[self.offsets[level] + idx] = parent;
+    }
+
+    //Children of node `idx` of layer `level`, fewer than the arity for the last node of an odd layer
+    fn children_of(&self, level: usize, idx: usize) -> &[H::Digest] {
+        let below = self.layer(level - 1).unwrap();
+        let first = idx * self.arity();
+        &below[first..below.len().min(first + self.arity())]
+    }
+
+    /// Proof of leaf `leaf_index` for any arity, one step per level
+    ///
+    /// A full group gives `arity - 1` siblings. A node promoted alone gives no step,
+    /// so like `proof_path` the proof can be shorter than `depth()`.
+    pub fn kary_proof(&self, leaf_index: usize) -> Option<Vec<KaryProofStep<H::Digest>>> {
+        if leaf_index >= self.num_leaves() { return None; }
+        let arity = self.arity();
+        let mut proof = Vec::with_capacity(self.depth());
+        let mut curr_idx = leaf_index;
+        for level in 0..self.depth() {
+            let children = complete_group::<H>(self.children_of(level + 1, curr_idx / arity), &self.config);
+            if children.len() > 1 {
+                let position = curr_idx % arity;
+                let siblings = children.iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != position)
+                    .map(|(_, child)| child.hash)
+                    .collect();
+                proof.push(KaryProofStep { position, siblings });
+            }
+            curr_idx /= arity;
+        }
+        Some(proof)
+    }
+
+    /// RFC 6962 consistency proof that the first `old_size` leaves had the root
+    /// of the tree built over them alone, i.e. that this tree only appended leaves
+    ///
+    /// Only binary `OddNodePolicy::Promote` trees have the RFC 6962 shape: others return None,
+    /// like an `old_size` of 0 or above `num_leaves()`.
+    pub fn consistency_proof(&self, old_size: usize) -> Option<Vec<H::Digest>> {
+        if self.config.odd_policy != OddNodePolicy::Promote || self.arity() != 2 || old_size == 0 || old_size > self.num_leaves() { return None; }
+        let mut proof = Vec::new();
+        self.subproof(old_size, 0, self.num_leaves(), true, &mut proof);
+        Some(proof)
+    }
+
+    //SUBPROOF(m, D[start:end], b) of RFC 6962 section 2.1.2
+    fn subproof(&self, m: usize, start: usize, end: usize, complete: bool, proof: &mut Vec<H::Digest>) {
+        let size = end - start;
+        if m == size {
+            //the old tree is a node of the new one: the verifier knows it unless it is the old root
+            if !complete { proof.push(self.range_hash(start, end)) }
+            return;
+        }
+        let k = largest_pow_of_two_below(size);
+        if m <= k {
+            self.subproof(m, start, start + k, complete, proof);
+            proof.push(self.range_hash(start + k, end));
+        } else {
+            self.subproof(m - k, start + k, end, false, proof);
+            proof.push(self.range_hash(start, start + k));
+        }
+    }
+
+    //MTH(D[start:end]): read from the layers when the range is a full node, split it otherwise
+    fn range_hash(&self, start: usize, end: usize) -> H::Digest {
+        let size = end - start;
+        if is_a_pow_of_two(size) && start.is_multiple_of(size) {
+            return self.node(size.trailing_zeros() as usize, start / size).unwrap();
+        }
+        let k = largest_pow_of_two_below(size);
+        let left = MerkleNode { hash: self.range_hash(start, start + k) };
+        let right = MerkleNode { hash: self.range_hash(start + k, end) };
+        MerkleNode::<H>::parent_with(&left, &right, self.config.domain).hash
+    }
 }
 
+//Index arithmetic inside a level: the parent sits one level up, the children one level down
+fn parent(idx: usize) -> usize {
+    idx / 2
+}
+
+fn sibling(idx: usize) -> usize {
+    idx ^ 1
+}
+
+//Where each level of a tree over `leaf_count` leaves starts in the flat storage, plus the end marker
+pub(crate) fn layer_offsets(leaf_count: usize, config: &TreeConfig) -> Vec<usize> {
+    checked_layer_offsets(leaf_count, config).expect("check_leaf_count rejects oversized trees")
+}
+
+//Same, None if the sizes overflow
+fn checked_layer_offsets(leaf_count: usize, config: &TreeConfig) -> Option<Vec<usize>> {
+    let mut width = padded_width(leaf_count, config)?;
+    let mut offsets = vec![0, width];
+    while width > 1 {
+        width = width.div_ceil(config.arity);
+        offsets.push(offsets.last().unwrap().checked_add(width)?);
+    }
+    Some(offsets)
+}
+
+//Width of layer 0: the leaves plus the padding of OddNodePolicy::PadWith, up to a power of the arity
+pub(crate) fn padded_width(leaf_count: usize, config: &TreeConfig) -> Option<usize> {
+    if !matches!(config.odd_policy, OddNodePolicy::PadWith(_)) { return Some(leaf_count); }
+    let mut width: usize = 1;
+    while width < leaf_count {
+        width = width.checked_mul(config.arity)?;
+    }
+    Some(width)
+}
+
+//Largest power of two strictly below n (n >= 2)
+fn largest_pow_of_two_below(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+//Hash a group of up to `arity` nodes into their parent; only the last group of an odd layer is short
+fn group_parent<H: MerkleHasher>(group: &[H::Digest], config: &TreeConfig) -> H::Digest {
+    //binary fast path, no allocation
+    if let ([left, right], 2) = (group, config.arity) {
+        return MerkleNode::<H>::parent_with(&MerkleNode { hash: *left }, &MerkleNode { hash: *right }, config.domain).hash;
+    }
+    match complete_group::<H>(group, config).as_slice() {
+        [promoted] => promoted.hash,
+        children => MerkleNode::from_children(children, config.domain).hash,
+    }
+}
+
+//Children actually hashed for a group of `arity` or fewer nodes
+fn complete_group<H: MerkleHasher>(group: &[H::Digest], config: &TreeConfig) -> Vec<MerkleNode<H>> {
+    let mut children: Vec<MerkleNode<H>> = group.iter().map(|hash| MerkleNode { hash: *hash }).collect();
+    match config.odd_policy {
+        OddNodePolicy::DuplicateLast => children.resize(config.arity, *children.last().unwrap()),
+        OddNodePolicy::Promote => {}
+        OddNodePolicy::PadWith(_) => assert_eq!(children.len(), config.arity, "padded layers are never odd"),
+    }
+    children
+}
+
+//Hash a layer group by group into the layer above
+fn parent_layer<H: MerkleHasher>(layer: &[H::Digest], config: &TreeConfig) -> Vec<H::Digest> {
+    layer.chunks(config.arity)
+        .map(|group| group_parent::<H>(group, config))
+        .collect()
+}
+
+//Below this many items per thread, spawning costs more than hashing
+const MIN_ITEMS_PER_THREAD: usize = 1024;
+
+//Run `work` over chunks of `items` (a multiple of `group` long) on up to `threads` threads and concatenate the results in order
+fn in_parallel<T: Sync, U: Send>(items: &[T], threads: usize, group: usize, work: impl Fn(&[T]) -> Vec<U> + Sync) -> Vec<U> {
+    let threads = threads.min(items.len() / MIN_ITEMS_PER_THREAD);
+    if threads <= 1 { return work(items); }
+    //whole groups keep every set of siblings inside one chunk
+    let chunk_size = items.len().div_ceil(threads).next_multiple_of(group);
+    thread::scope(|scope| {
+        let handles: Vec<_> = items.chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| work(chunk)))
+            .collect();
+        handles.into_iter()
+            .flat_map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect()
+    })
+}
+
+//What an unpaired last node of an odd layer becomes one level up (None if the policy never leaves one)
+pub(crate) fn lift_unpaired<H: MerkleHasher>(last: &MerkleNode<H>, config: &TreeConfig) -> Option<MerkleNode<H>> {
+    match config.odd_policy {
+        OddNodePolicy::DuplicateLast => Some(MerkleNode::parent_with(last, last, config.domain)),
+        OddNodePolicy::Promote => Some(*last),
+        OddNodePolicy::PadWith(_) => None,
+    }
+}
+
+/// One level of a k-ary proof: where the proven node sits among its
+/// siblings, and the hashes of those siblings, left to right
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KaryProofStep<D = Hash> {
+    pub position: usize,
+    pub siblings: Vec<D>,
+}
+
+/// Proof that several leaves belong to the same tree
+///
+/// The siblings are ordered level by level, left to right, so the verifier
+/// knows where each one goes without extra flags.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiProof<D = Hash> {
+    /// Proven leaf indices, sorted and without duplicates
+    pub indices: Vec<usize>,
+    /// Sibling hashes the verifier cannot recompute by itself
+    pub siblings: Vec<D>,
+}
+
+/// Proof that a contiguous run of leaves belongs to a tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeProof<D = Hash> {
+    /// Index of the first proven leaf
+    pub start: usize,
+    /// Siblings on the left edge of the range, bottom-up
+    pub left: Vec<D>,
+    /// Siblings on the right edge of the range, bottom-up
+    pub right: Vec<D>,
+}
 
 pub fn is_a_pow_of_two (n:usize) -> bool {
         (n!=0) && (n&(n-1))==0 //because of binary rep tricks
     }
 
+/// Recompute the root from a leaf and its proof path, whatever its length
 pub fn verify_proof(leaf: &[u8], proof: &[(Hash, SiblingDirection)], root: Hash) -> bool {
-    let mut curr = MerkleNode::leaf(leaf).hash;
+    verify_proof_with::<Sha256Hasher>(leaf, proof, root, HashDomain::Plain)
+}
+
+/// Same as `verify_proof` for a tree built with hasher `H` and the given hash domain
+pub fn verify_proof_with<H: MerkleHasher>(leaf: &[u8], proof: &[(H::Digest, SiblingDirection)], root: H::Digest, domain: HashDomain) -> bool {
+    root_from_proof::<H>(leaf, proof, domain) == root
+}
+
+/// Root obtained by hashing `leaf` up along its proof path
+pub fn root_from_proof<H: MerkleHasher>(leaf: &[u8], proof: &[(H::Digest, SiblingDirection)], domain: HashDomain) -> H::Digest {
+    let mut curr = MerkleNode::<H>::leaf_with(leaf, domain);
     for (sib, direction) in proof{
-        let mut hasher = Sha256::new();
-        match direction {
-            SiblingDirection::Left => {hasher.update(sib); hasher.update(curr)}
-            SiblingDirection::Right => {hasher.update(curr); hasher.update(sib)}
+        curr = hash_step(curr, *sib, *direction, domain);
+    }
+    curr.hash
+}
+
+//Hash a node with its sibling, on the side given by `direction`
+fn hash_step<H: MerkleHasher>(curr: MerkleNode<H>, sib: H::Digest, direction: SiblingDirection, domain: HashDomain) -> MerkleNode<H> {
+    let sib = MerkleNode { hash: sib };
+    match direction {
+        SiblingDirection::Left => MerkleNode::parent_with(&sib, &curr, domain),
+        SiblingDirection::Right => MerkleNode::parent_with(&curr, &sib, domain),
+    }
+}
+
+/// Check several leaves at once against `root`
+///
+/// `leaves[i]` is the data of leaf `proof.indices[i]`. `tree_size` is the trusted number
+/// of leaves and `config` the one the tree was built with: they fix the position of
+/// every node, so padding leaves can't be proven and indices can't be shifted.
+pub fn verify_multiproof<H: MerkleHasher>(leaves: &[&[u8]], tree_size: usize, proof: &MultiProof<H::Digest>, root: H::Digest, config: &TreeConfig) -> bool {
+    if leaves.is_empty() || leaves.len() != proof.indices.len() || check_leaf_count(tree_size, config).is_err() || config.arity != 2 { return false; }
+    //indices must be strictly increasing and real leaves
+    if proof.indices.windows(2).any(|w| w[0] >= w[1]) || *proof.indices.last().unwrap() >= tree_size { return false; }
+
+    let mut known: Vec<(usize, MerkleNode<H>)> = proof.indices.iter()
+        .zip(leaves)
+        .map(|(idx, leaf)| (*idx, MerkleNode::leaf_with(leaf, config.domain)))
+        .collect();
+    let mut siblings = proof.siblings.iter().map(|hash| MerkleNode::<H> { hash: *hash });
+    let mut width = layer_offsets(tree_size, config)[1];
+    while width > 1 {
+        let mut next = Vec::new();
+        let mut i = 0;
+        while i < known.len() {
+            let (idx, node) = known[i];
+            let parent = if idx % 2 == 1 {
+                let Some(left) = siblings.next() else { return false };
+                MerkleNode::parent_with(&left, &node, config.domain)
+            } else if i + 1 < known.len() && known[i+1].0 == idx + 1 {
+                i += 1;
+                MerkleNode::parent_with(&node, &known[i].1, config.domain)
+            } else if idx + 1 < width {
+                let Some(right) = siblings.next() else { return false };
+                MerkleNode::parent_with(&node, &right, config.domain)
+            } else {
+                let Some(lifted) = lift_unpaired(&node, config) else { return false };
+                lifted
+            };
+            next.push((idx / 2, parent));
+            i += 1;
         }
-        curr = hasher.finalize().into()
+        known = next;
+        width = width.div_ceil(2);
     }
-    curr == root
+    //every sibling must have been used
+    siblings.next().is_none() && known[0].1.hash == root
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Rebuild the root from the contiguous `leaves` starting at `proof.start`
+///
+/// `tree_size` is the trusted number of leaves and `config` the one the tree was
+/// built with: together they give the shape of every layer, so the range can't be
+/// moved by a forged width or stretched over padding leaves.
+#[allow(clippy::manual_is_multiple_of)]
+pub fn verify_range_proof<H: MerkleHasher>(leaves: &[&[u8]], tree_size: usize, proof: &RangeProof<H::Digest>, root: H::Digest, config: &TreeConfig) -> bool {
+    if leaves.is_empty() || check_leaf_count(tree_size, config).is_err() || config.arity != 2 { return false; }
+    //`start` comes from the prover: it must not overflow
+    match proof.start.checked_add(leaves.len()) {
+        Some(end) if end <= tree_size => {}
+        _ => return false,
+    }
+    let mut nodes: Vec<MerkleNode<H>> = leaves.iter().map(|leaf| MerkleNode::leaf_with(leaf, config.domain)).collect();
+    let (mut left, mut right) = (proof.left.iter(), proof.right.iter());
+    let mut lo = proof.start;
+    let mut width = layer_offsets(tree_size, config)[1];
+    while width > 1 {
+        //complete both edges so that the run starts and ends on whole pairs
+        if lo % 2 == 1 {
+            let Some(hash) = left.next() else { return false };
+            nodes.insert(0, MerkleNode { hash: *hash });
+            lo -= 1;
+        }
+        let hi = lo + nodes.len() - 1;
+        if hi % 2 == 0 && hi + 1 < width {
+            let Some(hash) = right.next() else { return false };
+            nodes.push(MerkleNode { hash: *hash });
+        }
+        let mut next = Vec::with_capacity(nodes.len().div_ceil(2));
+        for chunk in nodes.chunks(2) {
+            let parent = match chunk {
+                [l, r] => MerkleNode::parent_with(l, r, config.domain),
+                [last] => match lift_unpaired(last, config) {
+                    Some(lifted) => lifted,
+                    None => return false,
+                },
+                _ => unreachable!("chunks(2) yields 1 or 2 nodes"),
+            };
+            next.push(parent);
+        }
+        nodes = next;
+        lo /= 2;
+        width = width.div_ceil(2);
+    }
+    //every sibling must have been used
+    left.next().is_none() && right.next().is_none() && nodes[0].hash == root
+}
+
+/// Recompute the root from a leaf and its `kary_proof`, `config` being the one of the tree
+///
+/// Each step must hold at most `arity - 1` siblings and a position among them.
+pub fn verify_kary_proof<H: MerkleHasher>(leaf: &[u8], proof: &[KaryProofStep<H::Digest>], root: H::Digest, config: &TreeConfig) -> bool {
+    let mut curr = MerkleNode::<H>::leaf_with(leaf, config.domain);
+    for step in proof {
+        if step.siblings.is_empty() || step.siblings.len() >= config.arity || step.position > step.siblings.len() { return false; }
+        let mut children: Vec<MerkleNode<H>> = step.siblings.iter().map(|hash| MerkleNode { hash: *hash }).collect();
+        children.insert(step.position, curr);
+        curr = MerkleNode::from_children(&children, config.domain);
+    }
+    curr.hash == root
+}
+
+/// Check an RFC 6962 consistency proof between a tree of `old_size` leaves and its
+/// extension to `new_size` leaves (algorithm of RFC 9162, section 2.1.4.2)
+pub fn verify_consistency<H: MerkleHasher>(old_size: usize, new_size: usize, old_root: H::Digest, new_root: H::Digest, proof: &[H::Digest], domain: HashDomain) -> bool {
+    if old_size == 0 || old_size > new_size { return false; }
+    if old_size == new_size { return proof.is_empty() && old_root == new_root; }
+
+    //when the old tree is a full left subtree, its root is the first node of the path
+    let mut path = proof.iter().copied();
+    let first = if is_a_pow_of_two(old_size) { Some(old_root) } else { path.next() };
+    let Some(first) = first else { return false };
+
+    let (mut fn_, mut sn) = (old_size - 1, new_size - 1);
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let (mut fr, mut sr) = (MerkleNode::<H> { hash: first }, MerkleNode::<H> { hash: first });
+    for c in path {
+        if sn == 0 { return false; }
+        let c = MerkleNode { hash: c };
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = MerkleNode::parent_with(&c, &fr, domain);
+            sr = MerkleNode::parent_with(&c, &sr, domain);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = MerkleNode::parent_with(&sr, &c, domain);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && fr.hash == old_root && sr.hash == new_root
+}
+
+/// Why a tree could not be built or a proof did not verify
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MerkleError {
+    /// A tree needs at least one leaf
+    EmptyInput,
+    /// Too many leaves for the tree to be built (padding would overflow)
+    InvalidSize(usize),
+    /// An arity below 2
+    InvalidArity(usize),
+    /// The operation needs a binary tree, not one of this arity
+    NotBinary(usize),
+    IndexOutOfRange { index: usize, num_leaves: usize },
+    /// The proof doesn't have one sibling per hashing level of the leaf
+    ProofLengthMismatch { expected: usize, found: usize },
+    /// The sibling of proof step `step` is on the wrong side for the leaf index
+    DirectionMismatch { step: usize },
+    /// The recomputed node of level `level` is not the one of the tree
+    Diverged { level: usize },
+    /// The recomputed root is not the expected one
+    RootMismatch,
+}
+
+impl fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MerkleError::EmptyInput => write!(f, "the tree needs at least one leaf"),
+            MerkleError::InvalidSize(size) => write!(f, "cannot build a tree of {size} leaves"),
+            MerkleError::InvalidArity(arity) => write!(f, "invalid arity {arity}, a node needs at least 2 children"),
+            MerkleError::NotBinary(arity) => write!(f, "only binary trees support this, not arity {arity}"),
+            MerkleError::IndexOutOfRange { index, num_leaves } => write!(f, "leaf index {index} out of range for {num_leaves} leaves"),
+            MerkleError::ProofLengthMismatch { expected, found } => write!(f, "expected a proof of {expected} steps, found {found}"),
+            MerkleError::DirectionMismatch { step } => write!(f, "proof step {step} has the sibling on the wrong side"),
+            MerkleError::Diverged { level } => write!(f, "recomputed node diverges from the tree at level {level}"),
+            MerkleError::RootMismatch => write!(f, "recomputed root does not match"),
+        }
+    }
+}
+
+impl std::error::Error for MerkleError {}
+
+/// Same as `verify_proof_with`, explaining why a proof is rejected
+///
+/// The shape of the proof (its length and the side of each sibling) is checked against
+/// the position of leaf `leaf_index` in a tree of `tree_size` leaves built with `config`,
+/// then the recomputed root against `root`.
+pub fn verify_proof_detailed<H: MerkleHasher>(leaf: &[u8], leaf_index: usize, tree_size: usize, proof: &[(H::Digest, SiblingDirection)], root: H::Digest, config: &TreeConfig) -> Result<(), MerkleError> {
+    check_shape(leaf_index, tree_size, proof, config)?;
+    if root_from_proof::<H>(leaf, proof, config.domain) != root { return Err(MerkleError::RootMismatch); }
+    Ok(())
+}
+
+//A tree can be built over `leaf_count` leaves
+fn check_leaf_count(leaf_count: usize, config: &TreeConfig) -> Result<(), MerkleError> {
+    if config.arity < 2 { return Err(MerkleError::InvalidArity(config.arity)); }
+    if leaf_count == 0 { return Err(MerkleError::EmptyInput); }
+    if checked_layer_offsets(leaf_count, config).is_none() { return Err(MerkleError::InvalidSize(leaf_count)); }
+    Ok(())
+}
+
+//Check the length and directions of a proof, return for each level whether it consumes a proof step
+pub(crate) fn check_shape<D>(leaf_index: usize, tree_size: usize, proof: &[(D, SiblingDirection)], config: &TreeConfig) -> Result<Vec<bool>, MerkleError> {
+    check_leaf_count(tree_size, config)?;
+    if config.arity != 2 { return Err(MerkleError::NotBinary(config.arity)); }
+    if leaf_index >= tree_size { return Err(MerkleError::IndexOutOfRange { index: leaf_index, num_leaves: tree_size }); }
+    let offsets = layer_offsets(tree_size, config);
+    let mut steps = Vec::with_capacity(offsets.len() - 2);
+    let mut directions = Vec::with_capacity(offsets.len() - 2);
+    let mut curr_idx = leaf_index;
+    for bounds in offsets.windows(3) {
+        let width = bounds[1] - bounds[0];
+        let has_sibling = sibling(curr_idx) < width || config.odd_policy == OddNodePolicy::DuplicateLast;
+        if has_sibling {
+            directions.push(if curr_idx % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right});
+        }
+        steps.push(has_sibling);
+        curr_idx = parent(curr_idx);
+    }
+    if proof.len() != directions.len() {
+        return Err(MerkleError::ProofLengthMismatch { expected: directions.len(), found: proof.len() });
+    }
+    if let Some(step) = proof.iter().zip(&directions).position(|((_, found), expected)| found != expected) {
+        return Err(MerkleError::DirectionMismatch { step });
+    }
+    Ok(steps)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SiblingDirection {
     Left,
     Right,
@@ -126,12 +933,22 @@ pub enum SiblingDirection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::merkle_hasher::{Blake2sHasher, Keccak256Hasher, Sha512_256Hasher};
+    use sha2::{Digest, Sha256};
 
     // Tier 1: Basics
     #[test]
     #[should_panic]
-    fn test_single_leaf_panics() {
-        MerkleTree::new(vec![b"hello"]);
+    fn test_empty_panics() {
+        MerkleTree::new(vec![]);
+    }
+
+    #[test]
+    fn test_single_leaf() {
+        let tree = MerkleTree::new(vec![b"hello"]);
+        assert_eq!(tree.num_leaves(), 1);
+        assert_eq!(tree.depth(), 0);
+        assert_eq!(tree.root(), MerkleNode::leaf(b"hello").hash);
     }
 
     #[test]
@@ -150,9 +967,10 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_invalid_leaf_count_panics() {
-        MerkleTree::new(vec![b"a", b"b", b"c"]);
+    fn test_three_leaves() {
+        let tree = MerkleTree::new(vec![b"a", b"b", b"c"]);
+        assert_eq!(tree.num_leaves(), 3);
+        assert_eq!(tree.depth(), 2);
     }
 
     // Tier 2: Determinism
@@ -284,4 +1102,833 @@ fn test_proof_path_length_matches_depth() {
         assert!(!verify_proof(b"wrong", &proof, root), "Verification should fail for incorrect leaf data");
     }
 
-}
\ No newline at end of file
+    // Tier 7: Odd leaf counts
+    #[test]
+    fn test_odd_policies_roots() {
+        let (a, b, c) = (MerkleNode::leaf(b"a"), MerkleNode::leaf(b"b"), MerkleNode::leaf(b"c"));
+        let ab = MerkleNode::parent(&a, &b);
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+
+        // Promote: c moves up unchanged
+        let tree = MerkleTree::with_policy(data.clone(), OddNodePolicy::Promote);
+        assert_eq!(tree.root(), MerkleNode::parent(&ab, &c).hash);
+
+        // DuplicateLast: c is hashed with itself
+        let tree = MerkleTree::with_policy(data.clone(), OddNodePolicy::DuplicateLast);
+        assert_eq!(tree.root(), MerkleNode::parent(&ab, &MerkleNode::parent(&c, &c)).hash);
+
+        // PadWith: a 4th empty leaf is appended
+        let tree = MerkleTree::with_policy(data, OddNodePolicy::PadWith(b"empty".to_vec()));
+        let pad = MerkleNode::leaf(b"empty");
+        assert_eq!(tree.root(), MerkleNode::parent(&ab, &MerkleNode::parent(&c, &pad)).hash);
+        assert_eq!(tree.num_leaves(), 3);
+        assert!(tree.proof_path(3).is_none(), "padding leaves are not provable");
+    }
+
+    #[test]
+    fn test_policies_agree_on_power_of_two() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d"];
+        let root = MerkleTree::new(data.clone()).root();
+        assert_eq!(MerkleTree::with_policy(data.clone(), OddNodePolicy::DuplicateLast).root(), root);
+        assert_eq!(MerkleTree::with_policy(data, OddNodePolicy::PadWith(vec![])).root(), root);
+    }
+
+    #[test]
+    fn test_promoted_proof_is_shorter() {
+        let tree = MerkleTree::new(vec![b"a", b"b", b"c", b"d", b"e"]);
+        assert_eq!(tree.depth(), 3);
+        // "e" is promoted twice and only meets a sibling at the top
+        let proof = tree.proof_path(4).unwrap();
+        assert_eq!(proof.len(), 1);
+        assert_eq!(proof[0].1, SiblingDirection::Left);
+        assert!(verify_proof(b"e", &proof, tree.root()));
+    }
+
+    #[test]
+    fn test_every_policy_every_size_verifies() {
+        let policies = [
+            OddNodePolicy::DuplicateLast,
+            OddNodePolicy::Promote,
+            OddNodePolicy::PadWith(b"".to_vec()),
+        ];
+        for size in 1..=17 {
+            let data: Vec<&[u8]> = (0..size)
+                .map(|i| format!("item_{}", i))
+                .map(|s| Box::leak(s.into_boxed_str()).as_bytes())
+                .collect();
+            for policy in &policies {
+                let tree = MerkleTree::with_policy(data.clone(), policy.clone());
+                for (idx, leaf) in data.iter().enumerate() {
+                    let proof = tree.proof_path(idx).unwrap();
+                    assert!(verify_proof(leaf, &proof, tree.root()), "{policy:?}, size {size}, leaf {idx}");
+                    assert!(!verify_proof(b"wrong", &proof, tree.root()));
+                }
+                assert!(tree.proof_path(size).is_none());
+            }
+        }
+    }
+
+    // Tier 8: Domain separation
+    #[test]
+    fn test_rfc6962_prefixes() {
+        let mut hasher = Sha256::new();
+        hasher.update([0x00]);
+        hasher.update(b"a");
+        let expected: Hash = hasher.finalize().into();
+        assert_eq!(MerkleNode::<Sha256Hasher>::leaf_with(b"a", HashDomain::Rfc6962).hash, expected);
+        assert_ne!(MerkleNode::leaf(b"a").hash, expected);
+
+        let (l, r): (MerkleNode, MerkleNode) = (MerkleNode { hash: [1; 32] }, MerkleNode { hash: [2; 32] });
+        let mut hasher = Sha256::new();
+        hasher.update([0x01]);
+        hasher.update(l.hash);
+        hasher.update(r.hash);
+        let expected: Hash = hasher.finalize().into();
+        assert_eq!(MerkleNode::parent_with(&l, &r, HashDomain::Rfc6962).hash, expected);
+    }
+
+    #[test]
+    fn test_second_preimage_attack() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d"];
+        for domain in [HashDomain::Plain, HashDomain::Rfc6962] {
+            let config = TreeConfig { domain, ..TreeConfig::default() };
+            let tree = MerkleTree::with_config(data.clone(), config);
+            // Forged 64-byte "leaf": the two children of the internal node ab
+            let forged = [tree.node(0, 0).unwrap(), tree.node(0, 1).unwrap()].concat();
+            let proof = tree.proof_path(0).unwrap();
+            let accepted = verify_proof_with::<Sha256Hasher>(&forged, &proof[1..], tree.root(), domain);
+            assert_eq!(accepted, domain == HashDomain::Plain, "{domain:?}");
+        }
+    }
+
+    #[test]
+    fn test_domain_changes_root_and_is_honoured_by_proofs() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d", b"e"];
+        let plain = MerkleTree::new(data.clone());
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let tree = MerkleTree::with_config(data.clone(), config);
+            assert_eq!(tree.domain(), HashDomain::Rfc6962);
+            assert_ne!(tree.root(), plain.root());
+            for (idx, leaf) in data.iter().enumerate() {
+                let proof = tree.proof_path(idx).unwrap();
+                assert!(verify_proof_with::<Sha256Hasher>(leaf, &proof, tree.root(), HashDomain::Rfc6962));
+                assert!(!verify_proof(leaf, &proof, tree.root()), "plain verification must not accept it");
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_children_matches_parent() {
+        let (a, b) = (MerkleNode::leaf(b"a"), MerkleNode::leaf(b"b"));
+        for domain in [HashDomain::Plain, HashDomain::Rfc6962] {
+            assert_eq!(MerkleNode::from_children(&[a, b], domain), MerkleNode::parent_with(&a, &b, domain));
+        }
+    }
+
+    // Tier 9: Pluggable hashers
+    // A toy field hash (mod 2^61 - 1) with an 8-byte digest, standing in for ZK-friendly hashes
+    #[derive(Clone, Copy, Debug, Default)]
+    struct ToyFieldHasher;
+
+    impl MerkleHasher for ToyFieldHasher {
+        type Digest = [u8; 8];
+        const DIGEST_SIZE: usize = 8;
+        const ID: u8 = 0xf0;
+        const NAME: &'static str = "toy-field";
+
+        fn hash(parts: &[&[u8]]) -> Self::Digest {
+            const P: u128 = (1 << 61) - 1;
+            let mut acc: u128 = 7;
+            for byte in parts.iter().flat_map(|part| part.iter()) {
+                acc = (acc * 31 + *byte as u128 + 1) % P;
+                // x^5 S-box, reduced at each step to stay within u128
+                let x2 = acc * acc % P;
+                acc = x2 * x2 % P * acc % P;
+            }
+            (acc as u64).to_le_bytes()
+        }
+    }
+
+    // Same suite for every hasher
+    fn check_hasher<H: MerkleHasher>() {
+        for size in 1..=9 {
+            let data: Vec<&[u8]> = (0..size)
+                .map(|i| format!("item_{}", i))
+                .map(|s| Box::leak(s.into_boxed_str()).as_bytes())
+                .collect();
+            for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+                for domain in [HashDomain::Plain, HashDomain::Rfc6962] {
+                    let config = TreeConfig { odd_policy: odd_policy.clone(), domain, ..TreeConfig::default() };
+                    let tree = MerkleTree::<H>::build(data.clone(), config);
+                    assert_eq!(tree.num_leaves(), size);
+                    for (idx, leaf) in data.iter().enumerate() {
+                        let proof = tree.proof_path(idx).unwrap();
+                        assert!(verify_proof_with::<H>(leaf, &proof, tree.root(), domain));
+                        assert!(!verify_proof_with::<H>(b"wrong", &proof, tree.root(), domain));
+                    }
+                }
+            }
+        }
+        let tree1 = MerkleTree::<H>::build(vec![b"alice", b"bob"], TreeConfig::default());
+        let tree2 = MerkleTree::<H>::build(vec![b"alice", b"eve"], TreeConfig::default());
+        assert_ne!(tree1.root(), tree2.root());
+    }
+
+    #[test]
+    fn test_every_hasher() {
+        check_hasher::<Sha256Hasher>();
+        check_hasher::<Sha512_256Hasher>();
+        check_hasher::<Keccak256Hasher>();
+        check_hasher::<Blake2sHasher>();
+        check_hasher::<ToyFieldHasher>();
+    }
+
+    #[test]
+    fn test_default_hasher_is_sha256() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+        let tree = MerkleTree::new(data.clone());
+        let generic = MerkleTree::<Sha256Hasher>::build(data.clone(), TreeConfig::default());
+        assert_eq!(tree.root(), generic.root());
+        let keccak = MerkleTree::<Keccak256Hasher>::build(data, TreeConfig::default());
+        assert_ne!(tree.root(), keccak.root());
+    }
+
+    // Tier 10: Multiproofs
+    fn items(size: usize) -> Vec<&'static [u8]> {
+        (0..size)
+            .map(|i| format!("item_{}", i))
+            .map(|s| Box::leak(s.into_boxed_str()).as_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_multiproof_all_subsets() {
+        for size in 1..=9 {
+            let data = items(size);
+            for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+                let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+                let tree = MerkleTree::with_config(data.clone(), config.clone());
+                for mask in 1u32..(1 << size) {
+                    let indices: Vec<usize> = (0..size).filter(|i| mask & (1 << i) != 0).collect();
+                    let leaves: Vec<&[u8]> = indices.iter().map(|i| data[*i]).collect();
+                    let proof = tree.multiproof(&indices).unwrap();
+                    assert!(verify_multiproof::<Sha256Hasher>(&leaves, size, &proof, tree.root(), &config), "{config:?}, {indices:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_multiproof_is_compact() {
+        let data = items(1000);
+        let tree = MerkleTree::new(data.clone());
+        let indices: Vec<usize> = (0..500).map(|i| i * 2).collect();
+        let proof = tree.multiproof(&indices).unwrap();
+        let separate: usize = indices.iter().map(|i| tree.proof_path(*i).unwrap().len()).sum();
+        // Only the odd leaves of layer 0 are needed, every upper node is recomputed
+        assert_eq!(proof.siblings.len(), 500);
+        assert!(proof.siblings.len() * 5 < separate);
+        let leaves: Vec<&[u8]> = indices.iter().map(|i| data[*i]).collect();
+        assert!(verify_multiproof::<Sha256Hasher>(&leaves, 1000, &proof, tree.root(), &TreeConfig::default()));
+    }
+
+    #[test]
+    fn test_multiproof_sorts_and_dedups() {
+        let data = items(8);
+        let tree = MerkleTree::new(data.clone());
+        let proof = tree.multiproof(&[5, 1, 5, 3]).unwrap();
+        assert_eq!(proof.indices, vec![1, 3, 5]);
+        assert!(verify_multiproof::<Sha256Hasher>(&[data[1], data[3], data[5]], 8, &proof, tree.root(), &TreeConfig::default()));
+        assert!(tree.multiproof(&[]).is_none());
+        assert!(tree.multiproof(&[2, 8]).is_none());
+    }
+
+    #[test]
+    fn test_multiproof_rejects_tampering() {
+        let data = items(7);
+        let config = TreeConfig::default();
+        let tree = MerkleTree::new(data.clone());
+        let proof = tree.multiproof(&[0, 4, 6]).unwrap();
+        let leaves = [data[0], data[4], data[6]];
+        assert!(verify_multiproof::<Sha256Hasher>(&leaves, 7, &proof, tree.root(), &config));
+
+        // Wrong leaf data
+        assert!(!verify_multiproof::<Sha256Hasher>(&[data[0], data[4], b"x"], 7, &proof, tree.root(), &config));
+        // Leaves in the wrong order
+        assert!(!verify_multiproof::<Sha256Hasher>(&[data[4], data[0], data[6]], 7, &proof, tree.root(), &config));
+        // Missing or extra siblings
+        let mut short = proof.clone();
+        short.siblings.pop();
+        assert!(!verify_multiproof::<Sha256Hasher>(&leaves, 7, &short, tree.root(), &config));
+        let mut long = proof.clone();
+        long.siblings.push([0; 32]);
+        assert!(!verify_multiproof::<Sha256Hasher>(&leaves, 7, &long, tree.root(), &config));
+        // Unsorted indices
+        let mut unsorted = proof.clone();
+        unsorted.indices.swap(0, 1);
+        assert!(!verify_multiproof::<Sha256Hasher>(&leaves, 7, &unsorted, tree.root(), &config));
+        // Another odd-node policy gives another root
+        let dup = TreeConfig { odd_policy: OddNodePolicy::DuplicateLast, ..config };
+        assert!(!verify_multiproof::<Sha256Hasher>(&leaves, 7, &proof, tree.root(), &dup));
+    }
+
+    #[test]
+    fn test_multiproof_forged_width_rejected() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+        let config = TreeConfig::default();
+        let tree = MerkleTree::with_config(data.clone(), config.clone());
+        // Under a claimed width of 2, c sits at index 1 right of H(a, b)
+        let moved = MultiProof { indices: vec![1], siblings: vec![tree.node(1, 0).unwrap()] };
+        assert!(!verify_multiproof::<Sha256Hasher>(&[b"c"], 3, &moved, tree.root(), &config));
+
+        // The padding leaf at index 3 is in layer 0 but is not a leaf of the tree
+        let config = TreeConfig { odd_policy: OddNodePolicy::PadWith(b"pad".to_vec()), ..TreeConfig::default() };
+        let padded = MerkleTree::with_config(data, config.clone());
+        let padding = MultiProof { indices: vec![2, 3], siblings: vec![padded.node(1, 0).unwrap()] };
+        assert!(!verify_multiproof::<Sha256Hasher>(&[b"c", b"pad"], 3, &padding, padded.root(), &config));
+        let honest = padded.multiproof(&[2]).unwrap();
+        assert!(verify_multiproof::<Sha256Hasher>(&[b"c"], 3, &honest, padded.root(), &config));
+    }
+
+    // Tier 11: Consistency proofs (RFC 6962)
+    // Test vectors of the Certificate Transparency reference implementation
+    const RFC_LEAVES: [&str; 8] = [
+        "", "00", "10", "2021", "3031", "40414243",
+        "5051525354555657", "606162636465666768696a6b6c6d6e6f",
+    ];
+    const RFC_ROOTS: [&str; 8] = [
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+        "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+        "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+        "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+        "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+        "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+    ];
+
+    fn rfc_tree(size: usize) -> MerkleTree {
+        let leaves: Vec<Vec<u8>> = RFC_LEAVES.iter().map(|l| hex::decode(l).unwrap()).collect();
+        let data: Vec<&[u8]> = leaves[..size].iter().map(|l| l.as_slice()).collect();
+        MerkleTree::with_config(data, TreeConfig { odd_policy: OddNodePolicy::Promote, domain: HashDomain::Rfc6962, ..TreeConfig::default() })
+    }
+
+    fn unhex(hash: &str) -> Hash {
+        hex::decode(hash).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_rfc6962_roots() {
+        for size in 1..=8 {
+            assert_eq!(hex::encode(rfc_tree(size).root()), RFC_ROOTS[size - 1], "size {size}");
+        }
+    }
+
+    #[test]
+    fn test_rfc6962_consistency_vectors() {
+        let vectors: [(usize, usize, Vec<&str>); 4] = [
+            (1, 1, vec![]),
+            (1, 8, vec![
+                "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+            ]),
+            (6, 8, vec![
+                "0ebc5d3437fbe2db158b9f126a1d118e308181031d0a949f8dededebc558ef6a",
+                "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+                "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+            ]),
+            (2, 5, vec![
+                "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+                "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+            ]),
+        ];
+        for (old_size, new_size, expected) in vectors {
+            let proof = rfc_tree(new_size).consistency_proof(old_size).unwrap();
+            let expected: Vec<Hash> = expected.iter().map(|h| unhex(h)).collect();
+            assert_eq!(proof, expected, "{old_size} -> {new_size}");
+            let (old_root, new_root) = (unhex(RFC_ROOTS[old_size - 1]), unhex(RFC_ROOTS[new_size - 1]));
+            assert!(verify_consistency::<Sha256Hasher>(old_size, new_size, old_root, new_root, &proof, HashDomain::Rfc6962));
+        }
+    }
+
+    #[test]
+    fn test_consistency_every_pair() {
+        let data = items(20);
+        let roots: Vec<Hash> = (1..=data.len()).map(|n| MerkleTree::new(data[..n].to_vec()).root()).collect();
+        for new_size in 1..=data.len() {
+            let tree = MerkleTree::new(data[..new_size].to_vec());
+            for old_size in 1..=new_size {
+                let proof = tree.consistency_proof(old_size).unwrap();
+                let old_root = roots[old_size - 1];
+                assert!(verify_consistency::<Sha256Hasher>(old_size, new_size, old_root, tree.root(), &proof, HashDomain::Plain), "{old_size} -> {new_size}");
+                // A rewritten history does not verify
+                if old_size < new_size {
+                    assert!(!verify_consistency::<Sha256Hasher>(old_size, new_size, [0; 32], tree.root(), &proof, HashDomain::Plain));
+                    assert!(!verify_consistency::<Sha256Hasher>(old_size, new_size, old_root, [0; 32], &proof, HashDomain::Plain));
+                    assert!(!verify_consistency::<Sha256Hasher>(old_size, new_size, old_root, tree.root(), &proof[1..], HashDomain::Plain));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistency_proof_requires_rfc_shape() {
+        let data = items(5);
+        let tree = MerkleTree::new(data.clone());
+        assert!(tree.consistency_proof(0).is_none());
+        assert!(tree.consistency_proof(6).is_none());
+        let dup = MerkleTree::with_policy(data, OddNodePolicy::DuplicateLast);
+        assert!(dup.consistency_proof(3).is_none());
+    }
+
+    // Tier 12: In-place updates
+    #[test]
+    fn test_update_leaf_matches_rebuild() {
+        for size in 1..=9 {
+            let mut data = items(size);
+            for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+                let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+                let mut tree = MerkleTree::with_config(data.clone(), config.clone());
+                for idx in 0..size {
+                    assert_eq!(tree.update_leaf(idx, b"updated"), Ok(()));
+                    data[idx] = b"updated";
+                    let rebuilt = MerkleTree::with_config(data.clone(), config.clone());
+                    assert_eq!(tree.root(), rebuilt.root(), "{config:?}, size {size}, leaf {idx}");
+                    assert_eq!(tree.proof_path(idx), rebuilt.proof_path(idx));
+                }
+                data = items(size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_out_of_range() {
+        let mut tree = MerkleTree::with_policy(items(3), OddNodePolicy::PadWith(vec![]));
+        let root = tree.root();
+        assert_eq!(tree.update_leaf(3, b"padding leaf"), Err(MerkleError::IndexOutOfRange { index: 3, num_leaves: 3 }));
+        assert_eq!(tree.update_leaf_hash(7, [0; 32]), Err(MerkleError::IndexOutOfRange { index: 7, num_leaves: 3 }));
+        assert_eq!(tree.update_many(&[(0, b"x"), (3, b"y")]), Err(MerkleError::IndexOutOfRange { index: 3, num_leaves: 3 }));
+        assert_eq!(tree.root(), root);
+    }
+
+    #[test]
+    fn test_update_leaf_hash() {
+        let mut tree = MerkleTree::new(items(4));
+        let hash = MerkleNode::leaf(b"new").hash;
+        assert_eq!(tree.update_leaf_hash(2, hash), Ok(()));
+        assert_eq!(tree.root(), MerkleTree::new(vec![b"item_0", b"item_1", b"new", b"item_3"]).root());
+    }
+
+    #[test]
+    fn test_update_many_matches_rebuild() {
+        let mut data = items(11);
+        let mut tree = MerkleTree::new(data.clone());
+        assert_eq!(tree.update_many(&[(9, b"x"), (2, b"y"), (3, b"z"), (9, b"last wins")]), Ok(()));
+        data[2] = b"y";
+        data[3] = b"z";
+        data[9] = b"last wins";
+        assert_eq!(tree.root(), MerkleTree::new(data).root());
+    }
+
+    // Counts the hashes computed by the current test thread
+    thread_local! {
+        static HASH_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    #[derive(Clone, Copy, Debug, Default)]
+    struct CountingHasher;
+
+    impl MerkleHasher for CountingHasher {
+        type Digest = Hash;
+        const DIGEST_SIZE: usize = 32;
+        const ID: u8 = Sha256Hasher::ID;
+        const NAME: &'static str = Sha256Hasher::NAME;
+
+        fn hash(parts: &[&[u8]]) -> Self::Digest {
+            HASH_CALLS.with(|calls| calls.set(calls.get() + 1));
+            Sha256Hasher::hash(parts)
+        }
+    }
+
+    #[test]
+    fn test_update_many_shares_ancestors() {
+        let mut tree = MerkleTree::<CountingHasher>::build(items(8), TreeConfig::default());
+        let updates: Vec<(usize, &[u8])> = (0..8).map(|i| (i, &b"new"[..])).collect();
+        HASH_CALLS.with(|calls| calls.set(0));
+        assert_eq!(tree.update_many(&updates), Ok(()));
+        // 8 leaves + 4 + 2 + 1 parents, instead of 8 * (1 + 3) one leaf at a time
+        assert_eq!(HASH_CALLS.with(|calls| calls.get()), 15);
+
+        HASH_CALLS.with(|calls| calls.set(0));
+        assert_eq!(tree.update_leaf(5, b"other"), Ok(()));
+        assert_eq!(HASH_CALLS.with(|calls| calls.get()), 1 + 3);
+    }
+
+    // Tier 13: Parallel construction
+    #[test]
+    fn test_parallel_build_is_bit_identical() {
+        let data = items(9_001);
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let sequential = MerkleTree::with_config(data.clone(), config.clone());
+            for threads in [0, 1, 2, 3, 8] {
+                let parallel = MerkleTree::<Sha256Hasher>::build_parallel(data.clone(), config.clone(), threads);
+                assert_eq!(parallel.nodes, sequential.nodes, "{config:?}, {threads} threads");
+                assert_eq!(parallel.num_leaves(), sequential.num_leaves());
+                assert_eq!(parallel.proof_path(9_000), sequential.proof_path(9_000));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_build_small_trees() {
+        for size in 1..=9 {
+            let data = items(size);
+            let parallel = MerkleTree::<Sha256Hasher>::build_parallel(data.clone(), TreeConfig::default(), 4);
+            assert_eq!(parallel.root(), MerkleTree::new(data).root());
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parallel_build_empty_panics() {
+        MerkleTree::<Sha256Hasher>::build_parallel(vec![], TreeConfig::default(), 2);
+    }
+
+    // Tier 14: Flat storage
+    #[test]
+    fn test_layer_accessors() {
+        let data = items(5);
+        let tree = MerkleTree::with_policy(data.clone(), OddNodePolicy::PadWith(vec![]));
+        let widths: Vec<usize> = (0..=tree.depth()).map(|level| tree.layer(level).unwrap().len()).collect();
+        assert_eq!(widths, vec![8, 4, 2, 1]);
+        assert!(tree.layer(4).is_none());
+        assert_eq!(tree.leaves().len(), 5);
+        assert_eq!(tree.leaves()[4], MerkleNode::leaf(data[4]).hash);
+        assert_eq!(tree.node(0, 7), Some(MerkleNode::leaf(b"").hash));
+        assert_eq!(tree.node(tree.depth(), 0), Some(tree.root()));
+        assert!(tree.node(1, 4).is_none());
+
+        // Every parent is the hash of its two children
+        for level in 1..=tree.depth() {
+            for (idx, hash) in tree.layer(level).unwrap().iter().enumerate() {
+                let left = MerkleNode { hash: tree.node(level - 1, 2 * idx).unwrap() };
+                let right = MerkleNode { hash: tree.node(level - 1, 2 * idx + 1).unwrap() };
+                assert_eq!(*hash, MerkleNode::parent(&left, &right).hash);
+            }
+        }
+    }
+
+    // Flat storage against one Vec per layer on 2^24 leaves, run with
+    // cargo test --release --lib bench_flat_storage -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_flat_storage() {
+        use std::time::Instant;
+        let size = 1 << 24;
+        let data: Vec<[u8; 8]> = (0..size as u64).map(|i| i.to_le_bytes()).collect();
+        let start = Instant::now();
+        let tree = MerkleTree::new(data.iter().map(|d| d.as_slice()).collect());
+        println!("build: {:?}", start.elapsed());
+
+        // Same hashes, one allocation per layer
+        let layers: Vec<Vec<Hash>> = (0..=tree.depth()).map(|level| tree.layer(level).unwrap().to_vec()).collect();
+        let nested_proof = |mut idx: usize| -> Vec<(Hash, SiblingDirection)> {
+            layers[..layers.len() - 1].iter()
+                .map(|layer| {
+                    let direction = if idx % 2 == 1 {SiblingDirection::Left} else {SiblingDirection::Right};
+                    let step = (layer[idx ^ 1], direction);
+                    idx /= 2;
+                    step
+                })
+                .collect()
+        };
+        let flat_bytes = tree.nodes.capacity() * 32 + tree.offsets.capacity() * 8;
+        let nested_bytes: usize = layers.iter().map(|layer| layer.capacity() * 32 + 24).sum::<usize>() + 24;
+        println!("memory: flat {flat_bytes} bytes, nested {nested_bytes} bytes");
+        assert!(flat_bytes <= nested_bytes);
+
+        let indices: Vec<usize> = (0..100_000).map(|i| (i * 7919) % size).collect();
+        let start = Instant::now();
+        let flat: Vec<_> = indices.iter().map(|idx| tree.proof_path(*idx).unwrap()).collect();
+        println!("100k proofs, flat: {:?}", start.elapsed());
+        let start = Instant::now();
+        let nested: Vec<_> = indices.iter().map(|idx| nested_proof(*idx)).collect();
+        println!("100k proofs, nested: {:?}", start.elapsed());
+        assert_eq!(flat, nested);
+    }
+
+    #[test]
+    fn test_single_allocation() {
+        for size in [1, 2, 3, 7, 100, 1025] {
+            let tree = MerkleTree::new(items(size));
+            let total: usize = (0..=tree.depth()).map(|level| tree.layer(level).unwrap().len()).sum();
+            assert_eq!(tree.nodes.len(), total);
+            assert_eq!(tree.nodes.capacity(), total, "size {size}");
+        }
+    }
+
+    // Tier 15: Typed errors
+    #[test]
+    fn test_try_new() {
+        assert_eq!(MerkleTree::try_new(vec![]).err(), Some(MerkleError::EmptyInput));
+        let tree = MerkleTree::try_new(items(5)).unwrap();
+        assert_eq!(tree.root(), MerkleTree::new(items(5)).root());
+        assert_eq!(tree.try_proof_path(5), Err(MerkleError::IndexOutOfRange { index: 5, num_leaves: 5 }));
+        assert_eq!(tree.try_proof_path(4).ok(), tree.proof_path(4));
+        assert_eq!(MerkleError::EmptyInput.to_string(), "the tree needs at least one leaf");
+    }
+
+    #[test]
+    fn test_verify_proof_detailed() {
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let data = items(11);
+            let tree = MerkleTree::with_config(data.clone(), config.clone());
+            for (idx, leaf) in data.iter().enumerate() {
+                let proof = tree.proof_path(idx).unwrap();
+                assert_eq!(verify_proof_detailed::<Sha256Hasher>(leaf, idx, 11, &proof, tree.root(), &config), Ok(()));
+                assert_eq!(tree.verify_proof_detailed(leaf, idx, &proof), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_detailed_errors() {
+        let config = TreeConfig::default();
+        let data = items(6);
+        let tree = MerkleTree::new(data.clone());
+        let proof = tree.proof_path(2).unwrap();
+        let check = |leaf: &[u8], index, proof: &[(Hash, SiblingDirection)]| verify_proof_detailed::<Sha256Hasher>(leaf, index, 6, proof, tree.root(), &config);
+
+        assert_eq!(check(data[2], 6, &proof), Err(MerkleError::IndexOutOfRange { index: 6, num_leaves: 6 }));
+        assert_eq!(check(data[2], 2, &proof[1..]), Err(MerkleError::ProofLengthMismatch { expected: 3, found: 2 }));
+        // Leaf 4 is promoted once: its proof is shorter
+        assert_eq!(check(data[4], 4, &proof), Err(MerkleError::ProofLengthMismatch { expected: 2, found: 3 }));
+        assert_eq!(check(data[3], 3, &proof), Err(MerkleError::DirectionMismatch { step: 0 }));
+        assert_eq!(check(b"forged", 2, &proof), Err(MerkleError::RootMismatch));
+        assert_eq!(verify_proof_detailed::<Sha256Hasher>(b"a", 0, 0, &[], tree.root(), &config), Err(MerkleError::EmptyInput));
+    }
+
+    #[test]
+    fn test_divergence_level() {
+        let data = items(8);
+        let tree = MerkleTree::new(data.clone());
+        let mut proof = tree.proof_path(5).unwrap();
+        assert_eq!(tree.verify_proof_detailed(b"forged", 5, &proof), Err(MerkleError::Diverged { level: 0 }));
+        // Corrupt the sibling used to go from level 1 to level 2
+        proof[1].0[0] ^= 1;
+        assert_eq!(tree.verify_proof_detailed(data[5], 5, &proof), Err(MerkleError::Diverged { level: 2 }));
+    }
+
+    // Tier 16: Range proofs
+    #[test]
+    fn test_range_proofs_all_ranges() {
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let data = items(13);
+            let tree = MerkleTree::with_config(data.clone(), config.clone());
+            for start in 0..13 {
+                for end in start + 1..=13 {
+                    let proof = tree.range_proof(start..end).unwrap();
+                    assert!(verify_range_proof::<Sha256Hasher>(&data[start..end], 13, &proof, tree.root(), &config), "{config:?}, {start}..{end}");
+                }
+            }
+            assert!(tree.range_proof(3..3).is_none());
+            assert!(tree.range_proof(10..14).is_none());
+        }
+    }
+
+    #[test]
+    fn test_range_proof_only_sends_edges() {
+        let data = items(1024);
+        let tree = MerkleTree::new(data.clone());
+        let proof = tree.range_proof(100..200).unwrap();
+        assert!(proof.left.len() + proof.right.len() <= 2 * tree.depth());
+        // A whole aligned subtree needs only the path of its root
+        let aligned = tree.range_proof(256..512).unwrap();
+        assert_eq!(aligned.left.len() + aligned.right.len(), tree.depth() - 8);
+        assert!(verify_range_proof::<Sha256Hasher>(&data[256..512], 1024, &aligned, tree.root(), &TreeConfig::default()));
+    }
+
+    #[test]
+    fn test_range_proof_rejects_tampering() {
+        let data = items(20);
+        let tree = MerkleTree::new(data.clone());
+        let config = TreeConfig::default();
+        let proof = tree.range_proof(5..9).unwrap();
+        assert!(verify_range_proof::<Sha256Hasher>(&data[5..9], 20, &proof, tree.root(), &config));
+
+        let mut forged = data[5..9].to_vec();
+        forged[2] = b"forged";
+        assert!(!verify_range_proof::<Sha256Hasher>(&forged, 20, &proof, tree.root(), &config));
+        // Same data claimed at another position, or with a leaf missing
+        assert!(!verify_range_proof::<Sha256Hasher>(&data[5..9], 20, &RangeProof { start: 6, ..proof.clone() }, tree.root(), &config));
+        assert!(!verify_range_proof::<Sha256Hasher>(&data[5..8], 20, &proof, tree.root(), &config));
+        assert!(!verify_range_proof::<Sha256Hasher>(&[], 20, &proof, tree.root(), &config));
+    }
+
+    #[test]
+    fn test_range_proof_start_overflow_rejected() {
+        let data = items(8);
+        let tree = MerkleTree::new(data.clone());
+        let proof = tree.range_proof(2..5).unwrap();
+        let overflowing = RangeProof { start: usize::MAX, ..proof };
+        assert!(!verify_range_proof::<Sha256Hasher>(&data[2..5], 8, &overflowing, tree.root(), &TreeConfig::default()));
+    }
+
+    #[test]
+    fn test_range_proof_forged_width_rejected() {
+        let data: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+        let promote = MerkleTree::with_policy(data.clone(), OddNodePolicy::Promote);
+        let config = TreeConfig::default();
+        let ab = promote.node(1, 0).unwrap();
+        // Under a claimed width of 2, c sits at index 1 right of H(a, b)
+        let moved = RangeProof { start: 1, left: vec![ab], right: vec![] };
+        assert!(!verify_range_proof::<Sha256Hasher>(&[b"c"], 3, &moved, promote.root(), &config));
+
+        // The padding leaf at index 3 is in layer 0 but is not a leaf of the tree
+        let config = TreeConfig { odd_policy: OddNodePolicy::PadWith(b"pad".to_vec()), ..TreeConfig::default() };
+        let padded = MerkleTree::with_config(data, config.clone());
+        let padding = RangeProof { start: 3, left: vec![padded.node(0, 2).unwrap(), padded.node(1, 0).unwrap()], right: vec![] };
+        assert!(!verify_range_proof::<Sha256Hasher>(&[b"pad"], 3, &padding, padded.root(), &config));
+        let honest = padded.range_proof(2..3).unwrap();
+        assert!(verify_range_proof::<Sha256Hasher>(&[b"c"], 3, &honest, padded.root(), &config));
+    }
+
+    // Tier 17: Prehashed leaves
+    #[test]
+    fn test_from_leaf_hashes() {
+        let data = items(11);
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"pad".to_vec())] {
+            let config = TreeConfig { odd_policy, domain: HashDomain::Rfc6962, ..TreeConfig::default() };
+            let hashes: Vec<Hash> = data.iter().map(|d| MerkleNode::<Sha256Hasher>::leaf_with(d, config.domain).hash).collect();
+            let tree = MerkleTree::<Sha256Hasher>::from_leaf_hashes(hashes, config.clone()).unwrap();
+            assert_eq!(tree.root(), MerkleTree::with_config(data.clone(), config).root());
+        }
+        assert_eq!(MerkleTree::<Sha256Hasher>::from_leaf_hashes(vec![], TreeConfig::default()).err(), Some(MerkleError::EmptyInput));
+    }
+
+    // Tier 18: k-ary trees
+    fn arity(arity: usize, odd_policy: OddNodePolicy) -> TreeConfig {
+        TreeConfig { odd_policy, arity, ..TreeConfig::default() }
+    }
+
+    #[test]
+    fn test_from_children_of_two_is_parent() {
+        let (a, b) = (MerkleNode::leaf(b"a"), MerkleNode::leaf(b"b"));
+        for domain in [HashDomain::Plain, HashDomain::Rfc6962] {
+            assert_eq!(MerkleNode::from_children(&[a, b], domain), MerkleNode::parent_with(&a, &b, domain));
+        }
+    }
+
+    #[test]
+    fn test_kary_proofs_verify() {
+        for k in [2, 3, 4, 8, 16] {
+            for size in [1, 2, 5, 16, 17, 70] {
+                let data = items(size);
+                for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(b"pad".to_vec())] {
+                    let config = arity(k, odd_policy);
+                    let tree = MerkleTree::<Blake2sHasher>::build(data.clone(), config.clone());
+                    assert_eq!(tree.arity(), k);
+                    for (idx, leaf) in data.iter().enumerate() {
+                        let proof = tree.kary_proof(idx).unwrap();
+                        assert!(verify_kary_proof::<Blake2sHasher>(leaf, &proof, tree.root(), &config), "{config:?}, size {size}, leaf {idx}");
+                        assert!(!verify_kary_proof::<Blake2sHasher>(b"forged", &proof, tree.root(), &config));
+                    }
+                    assert!(tree.kary_proof(size).is_none());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_kary_proof_matches_proof_path() {
+        let data = items(13);
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let tree = MerkleTree::with_policy(data.clone(), odd_policy.clone());
+            for idx in 0..13 {
+                let steps: Vec<(Hash, SiblingDirection)> = tree.kary_proof(idx).unwrap().iter()
+                    .map(|step| (step.siblings[0], if step.position == 1 {SiblingDirection::Left} else {SiblingDirection::Right}))
+                    .collect();
+                assert_eq!(Some(steps), tree.proof_path(idx), "{odd_policy:?}, leaf {idx}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_wider_trees_are_shallower() {
+        let data = items(4096);
+        let binary = MerkleTree::new(data.clone());
+        let hex = MerkleTree::with_config(data, arity(16, OddNodePolicy::Promote));
+        assert_eq!(binary.depth(), 12);
+        assert_eq!(hex.depth(), 3);
+        assert_eq!(hex.layer(1).unwrap().len(), 256);
+        let proof = hex.kary_proof(1234).unwrap();
+        assert_eq!(proof.len(), 3);
+        assert!(proof.iter().all(|step| step.siblings.len() == 15));
+        // 1234 = 4 * 256 + 13 * 16 + 2
+        let positions: Vec<usize> = proof.iter().map(|step| step.position).collect();
+        assert_eq!(positions, vec![2, 13, 4]);
+    }
+
+    #[test]
+    fn test_kary_updates_and_parallel_build() {
+        let data = items(70);
+        for odd_policy in [OddNodePolicy::DuplicateLast, OddNodePolicy::Promote, OddNodePolicy::PadWith(vec![])] {
+            let config = arity(4, odd_policy);
+            let mut tree = MerkleTree::with_config(data.clone(), config.clone());
+            let mut changed = data.clone();
+            changed[69] = b"last";
+            changed[3] = b"third";
+            assert_eq!(tree.update_leaf(69, b"last"), Ok(()));
+            assert_eq!(tree.update_many(&[(3, b"third")]), Ok(()));
+            assert_eq!(tree.root(), MerkleTree::with_config(changed, config.clone()).root(), "{config:?}");
+            assert_eq!(tree.first_bad_node(), None);
+            let many = items(5000);
+            let parallel = MerkleTree::<Sha256Hasher>::build_parallel(many.clone(), config.clone(), 4);
+            assert_eq!(parallel.root(), MerkleTree::with_config(many, config).root());
+        }
+    }
+
+    #[test]
+    fn test_binary_only_proofs_refuse_other_arities() {
+        let config = arity(4, OddNodePolicy::Promote);
+        let tree = MerkleTree::with_config(items(10), config.clone());
+        assert!(tree.proof_path(0).is_none());
+        assert_eq!(tree.try_proof_path(0), Err(MerkleError::NotBinary(4)));
+        assert!(tree.multiproof(&[0, 1]).is_none());
+        assert!(tree.range_proof(0..2).is_none());
+        assert!(tree.consistency_proof(4).is_none());
+        assert_eq!(verify_proof_detailed::<Sha256Hasher>(b"item_0", 0, 10, &[], tree.root(), &config), Err(MerkleError::NotBinary(4)));
+    }
+
+    #[test]
+    fn test_malformed_kary_steps_rejected() {
+        let config = arity(4, OddNodePolicy::Promote);
+        let tree = MerkleTree::with_config(items(16), config.clone());
+        let proof = tree.kary_proof(6).unwrap();
+        assert!(verify_kary_proof::<Sha256Hasher>(b"item_6", &proof, tree.root(), &config));
+
+        let mut moved = proof.clone();
+        moved[0].position = 0;
+        assert!(!verify_kary_proof::<Sha256Hasher>(b"item_6", &moved, tree.root(), &config));
+        let mut outside = proof.clone();
+        outside[0].position = 4;
+        assert!(!verify_kary_proof::<Sha256Hasher>(b"item_6", &outside, tree.root(), &config));
+        // More siblings than the arity allows
+        assert!(!verify_kary_proof::<Sha256Hasher>(b"item_6", &proof, tree.root(), &arity(3, OddNodePolicy::Promote)));
+    }
+
+    #[test]
+    fn test_invalid_arity_and_overflowing_padding() {
+        assert_eq!(MerkleTree::<Sha256Hasher>::try_build(items(3), arity(1, OddNodePolicy::Promote)).err(), Some(MerkleError::InvalidArity(1)));
+        let pad = |k| arity(k, OddNodePolicy::PadWith(vec![]));
+        assert_eq!(checked_layer_offsets((1 << 40) + 1, &pad(1 << 40)), None);
+        assert_eq!(checked_layer_offsets(usize::MAX, &pad(3)), None);
+        assert_eq!(checked_layer_offsets(10, &pad(3)), Some(vec![0, 27, 36, 39, 40]));
+        assert_eq!(check_leaf_count(usize::MAX, &pad(3)), Err(MerkleError::InvalidSize(usize::MAX)));
+    }
+}
@@ -0,0 +1,133 @@
+/// Recursive Length Prefix encoding, as used by Ethereum
+///
+/// An item is either a byte string or a list of items. Only the canonical
+/// (shortest) encoding of an item is accepted by `decode`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RlpItem<'a> {
+    Bytes(&'a [u8]),
+    List(Vec<RlpItem<'a>>),
+}
+
+/// Encode a byte string
+pub fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+    //a single byte below 0x80 is its own encoding
+    if let [byte @ 0x00..=0x7f] = bytes { return vec![*byte]; }
+    let mut out = length_prefix(0x80, bytes.len());
+    out.extend_from_slice(bytes);
+    out
+}
+
+/// Encode a list whose items are already encoded
+pub fn encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload_len = items.iter().map(Vec::len).sum();
+    let mut out = length_prefix(0xc0, payload_len);
+    for item in items {
+        out.extend_from_slice(item);
+    }
+    out
+}
+
+//Short form below 56 bytes, otherwise the big-endian length follows the prefix
+fn length_prefix(offset: u8, len: usize) -> Vec<u8> {
+    if len < 56 { return vec![offset + len as u8]; }
+    let be = len.to_be_bytes();
+    let len_bytes = &be[len.leading_zeros() as usize / 8..];
+    let mut out = vec![offset + 55 + len_bytes.len() as u8];
+    out.extend_from_slice(len_bytes);
+    out
+}
+
+/// Decode exactly one item, None if `bytes` is not its canonical encoding
+pub fn decode(bytes: &[u8]) -> Option<RlpItem<'_>> {
+    match decode_item(bytes)? {
+        (item, []) => Some(item),
+        _ => None,
+    }
+}
+
+//Decode the first item of `bytes`, return it with what follows it
+fn decode_item(bytes: &[u8]) -> Option<(RlpItem<'_>, &[u8])> {
+    let (&prefix, rest) = bytes.split_first()?;
+    match prefix {
+        0x00..=0x7f => Some((RlpItem::Bytes(&bytes[..1]), rest)),
+        0x80..=0xbf => {
+            let (payload, rest) = payload(prefix - 0x80, rest)?;
+            //single bytes below 0x80 must use the short form
+            if let [0x00..=0x7f] = payload { return None; }
+            Some((RlpItem::Bytes(payload), rest))
+        }
+        0xc0..=0xff => {
+            let (mut payload, rest) = payload(prefix - 0xc0, rest)?;
+            let mut items = Vec::new();
+            while !payload.is_empty() {
+                let (item, tail) = decode_item(payload)?;
+                items.push(item);
+                payload = tail;
+            }
+            Some((RlpItem::List(items), rest))
+        }
+    }
+}
+
+//Split the payload announced by `len_code` (prefix minus its offset) off `bytes`
+fn payload(len_code: u8, bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = if len_code < 56 {
+        len_code as usize
+    } else {
+        let (len_bytes, rest) = bytes.split_at_checked((len_code - 55) as usize)?;
+        //no leading zero, and long form only from 56 bytes
+        if len_bytes[0] == 0 || len_bytes.len() > size_of::<usize>() { return None; }
+        let len = len_bytes.iter().fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+        if len < 56 { return None; }
+        return rest.split_at_checked(len);
+    };
+    bytes.split_at_checked(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_encodings() {
+        assert_eq!(encode_bytes(b""), vec![0x80]);
+        assert_eq!(encode_bytes(&[0x0f]), vec![0x0f]);
+        assert_eq!(encode_bytes(&[0x80]), vec![0x81, 0x80]);
+        assert_eq!(encode_bytes(b"dog"), vec![0x83, b'd', b'o', b'g']);
+        let cat_dog = encode_list(&[encode_bytes(b"cat"), encode_bytes(b"dog")]);
+        assert_eq!(cat_dog, vec![0xc8, 0x83, b'c', b'a', b't', 0x83, b'd', b'o', b'g']);
+        assert_eq!(encode_list(&[]), vec![0xc0]);
+
+        let lorem = b"Lorem ipsum dolor sit amet, consectetur adipisicing elit";
+        let encoded = encode_bytes(lorem);
+        assert_eq!(&encoded[..2], &[0xb8, 0x38]);
+        assert_eq!(&encoded[2..], lorem);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let long = vec![0xaa; 1024];
+        let nested = encode_list(&[
+            encode_bytes(b"cat"),
+            encode_list(&[encode_bytes(b""), encode_bytes(&long)]),
+        ]);
+        let expected = RlpItem::List(vec![
+            RlpItem::Bytes(b"cat"),
+            RlpItem::List(vec![RlpItem::Bytes(b""), RlpItem::Bytes(&long)]),
+        ]);
+        assert_eq!(decode(&nested), Some(expected));
+    }
+
+    #[test]
+    fn test_non_canonical_rejected() {
+        // 0x05 written with the one-byte string prefix
+        assert_eq!(decode(&[0x81, 0x05]), None);
+        // 3 bytes written with the long form
+        assert_eq!(decode(&[0xb8, 0x03, 1, 2, 3]), None);
+        // Leading zero in the length
+        assert_eq!(decode(&[0xb9, 0x00, 0x38]), None);
+        // Truncated payload, trailing bytes
+        assert_eq!(decode(&[0x83, b'd', b'o']), None);
+        assert_eq!(decode(&[0x80, 0x80]), None);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::merkle_hasher::MerkleHasher;
+use crate::merkle_tree::{root_from_proof, HashDomain, SiblingDirection};
+
+/// Number of roots kept by default, as in Tornado Cash
+pub const ROOT_HISTORY_SIZE: usize = 30;
+
+/// Ring buffer of the last roots of a tree
+///
+/// Once full, each new root overwrites the oldest one, so a proof built
+/// against a root up to `capacity() - 1` updates old is still accepted.
+#[derive(Clone, Debug)]
+pub struct RootHistory<D> {
+    roots: Vec<D>,
+    capacity: usize,
+    //slot of the latest root
+    current: usize,
+}
+
+impl<D: Copy + Eq> RootHistory<D> {
+    /// Empty history keeping up to `capacity` roots
+    pub fn new(capacity: usize) -> Self {
+        if capacity == 0 {panic!("The history must keep at least one root!")}
+        RootHistory { roots: Vec::with_capacity(capacity), capacity, current: 0 }
+    }
+
+    /// Record a new root, dropping the oldest one if the history is full
+    pub fn push(&mut self, root: D) {
+        if self.roots.len() < self.capacity {
+            self.current = self.roots.len();
+            self.roots.push(root);
+        } else {
+            self.current = (self.current + 1) % self.capacity;
+            self.roots[self.current] = root;
+        }
+    }
+
+    /// Return true if `root` is one of the kept roots
+    pub fn is_known_root(&self, root: &D) -> bool {
+        self.roots.contains(root)
+    }
+
+    /// Return the latest root
+    pub fn latest(&self) -> Option<D> {
+        self.roots.get(self.current).copied()
+    }
+
+    /// Iterate over the kept roots, latest first
+    pub fn iter(&self) -> impl Iterator<Item = &D> {
+        //slots after `current` hold the oldest roots
+        let (newest, oldest) = self.roots.split_at((self.current + 1).min(self.roots.len()));
+        newest.iter().rev().chain(oldest.iter().rev())
+    }
+
+    /// Return the number of kept roots
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// Return the maximum number of kept roots
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// Same as `verify_proof_with`, accepting any root still in `history`
+pub fn verify_proof_recent<H: MerkleHasher>(leaf: &[u8], proof: &[(H::Digest, SiblingDirection)], history: &RootHistory<H::Digest>, domain: HashDomain) -> bool {
+    history.is_known_root(&root_from_proof::<H>(leaf, proof, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer_drops_oldest() {
+        let mut history = RootHistory::new(3);
+        assert!(history.is_empty());
+        assert_eq!(history.latest(), None);
+        assert_eq!(history.iter().count(), 0);
+        for root in 1..=5u8 {
+            history.push([root; 4]);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.latest(), Some([5; 4]));
+        assert!(!history.is_known_root(&[2; 4]));
+        assert!(history.is_known_root(&[3; 4]));
+        let newest_first: Vec<_> = history.iter().copied().collect();
+        assert_eq!(newest_first, vec![[5; 4], [4; 4], [3; 4]]);
+    }
+
+    #[test]
+    fn test_partially_filled() {
+        let mut history = RootHistory::new(ROOT_HISTORY_SIZE);
+        history.push(7u32);
+        history.push(8);
+        assert_eq!(history.iter().copied().collect::<Vec<_>>(), vec![8, 7]);
+        assert_eq!(history.capacity(), 30);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_capacity_panics() {
+        RootHistory::<u8>::new(0);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use crate::merkle_hasher::{MerkleHasher, Sha256Hasher};
+use crate::merkle_tree::{verify_proof_with, Hash, HashDomain, MerkleNode, SiblingDirection};
+use crate::sparse_nodes::SparseNodes;
+
+/// Number of levels of the sparse tree: one per bit of a `Hash` key
+pub const DEPTH: usize = 256;
+
+/// Sparse Merkle tree with 2^256 slots, one per 32-byte key
+///
+/// Only the nodes that differ from an empty subtree are stored: an empty
+/// subtree of height h always hashes to the precomputed `zeros[h]`.
+/// An empty slot holds the empty leaf (`b""`), so storing an empty value is the same as removing the key.
+pub struct SparseMerkleTree<H: MerkleHasher = Sha256Hasher> {
+    domain: HashDomain,
+    //nodes keyed by (height, key with its `height` lowest bits cleared)
+    nodes: SparseNodes<Hash, H::Digest>,
+    values: HashMap<Hash, Vec<u8>>,
+}
+
+impl SparseMerkleTree {
+    /// Empty SHA-256 tree
+    pub fn new() -> Self {
+        Self::build(HashDomain::Plain)
+    }
+}
+
+impl Default for SparseMerkleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: MerkleHasher> SparseMerkleTree<H> {
+    /// Empty tree with any hasher and hash domain
+    pub fn build(domain: HashDomain) -> Self {
+        SparseMerkleTree { domain, nodes: SparseNodes::new::<H>(DEPTH, domain), values: HashMap::new() }
+    }
+
+    /// Return the root hash
+    pub fn root(&self) -> H::Digest {
+        self.nodes.get(DEPTH, [0; 32])
+    }
+
+    /// Return the number of keys with a value
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Return the value stored under `key`
+    pub fn get(&self, key: &Hash) -> Option<&[u8]> {
+        self.values.get(key).map(|value| value.as_slice())
+    }
+
+    pub fn contains_key(&self, key: &Hash) -> bool {
+        self.values.contains_key(key)
+    }
+
+    /// Store `value` under `key` and return the previous value
+    pub fn insert(&mut self, key: Hash, value: &[u8]) -> Option<Vec<u8>> {
+        if value.is_empty() { return self.remove(&key); }
+        self.update_path(&key, MerkleNode::leaf_with(value, self.domain));
+        self.values.insert(key, value.to_vec())
+    }
+
+    /// Empty the slot of `key` and return its value
+    pub fn remove(&mut self, key: &Hash) -> Option<Vec<u8>> {
+        let old = self.values.remove(key)?;
+        self.update_path(key, MerkleNode { hash: self.nodes.zero(0) });
+        Some(old)
+    }
+
+    /// Authentication path of the slot of `key`, from the leaf up
+    ///
+    /// It proves the stored value if the key is present and the empty leaf if it is absent.
+    pub fn proof_path(&self, key: &Hash) -> Vec<(H::Digest, SiblingDirection)> {
+        (0..DEPTH)
+            .map(|height| {
+                let sibling = self.nodes.get(height, prefix(&sibling_key(key, height), height));
+                (sibling, direction(key, height))
+            })
+            .collect()
+    }
+
+    //Rewrite the 257 nodes from the slot of `key` up to the root
+    fn update_path(&mut self, key: &Hash, leaf: MerkleNode<H>) {
+        let mut current = leaf;
+        self.nodes.set(0, *key, current.hash);
+        for height in 0..DEPTH {
+            let sibling = MerkleNode { hash: self.nodes.get(height, prefix(&sibling_key(key, height), height)) };
+            current = match direction(key, height) {
+                SiblingDirection::Left => MerkleNode::parent_with(&sibling, &current, self.domain),
+                SiblingDirection::Right => MerkleNode::parent_with(&current, &sibling, self.domain),
+            };
+            self.nodes.set(height + 1, prefix(key, height + 1), current.hash);
+        }
+    }
+}
+
+//Bit `height` of the key, counted from the least significant bit (the leaf level)
+fn bit(key: &Hash, height: usize) -> bool {
+    (key[31 - height / 8] >> (height % 8)) & 1 == 1
+}
+
+//Where the sibling sits at `height`: a node whose bit is set is a right child
+fn direction(key: &Hash, height: usize) -> SiblingDirection {
+    if bit(key, height) {SiblingDirection::Left} else {SiblingDirection::Right}
+}
+
+//The key with bit `height` flipped, i.e. a key under the sibling subtree
+fn sibling_key(key: &Hash, height: usize) -> Hash {
+    let mut sibling = *key;
+    sibling[31 - height / 8] ^= 1 << (height % 8);
+    sibling
+}
+
+//Identify the subtree of height `height` holding `key`: clear its `height` lowest bits,
+//whole trailing bytes first, then the low bits of the partial byte
+fn prefix(key: &Hash, height: usize) -> Hash {
+    let mut prefix = *key;
+    let (full_bytes, partial_bits) = (height / 8, height % 8);
+    prefix[32 - full_bytes..].fill(0);
+    if partial_bits > 0 { prefix[31 - full_bytes] &= 0xff << partial_bits; }
+    prefix
+}
+
+//A proof for `key` must take the turns given by its bits, otherwise it proves another slot
+fn follows_key<D>(key: &Hash, proof: &[(D, SiblingDirection)]) -> bool {
+    proof.len() == DEPTH && proof.iter().enumerate().all(|(height, (_, dir))| *dir == direction(key, height))
+}
+
+/// Check that `key` holds `value` in the tree of root `root`
+pub fn verify_membership<H: MerkleHasher>(key: &Hash, value: &[u8], proof: &[(H::Digest, SiblingDirection)], root: H::Digest, domain: HashDomain) -> bool {
+    !value.is_empty() && follows_key(key, proof) && verify_proof_with::<H>(value, proof, root, domain)
+}
+
+/// Check that `key` has no value in the tree of root `root`
+pub fn verify_non_membership<H: MerkleHasher>(key: &Hash, proof: &[(H::Digest, SiblingDirection)], root: H::Digest, domain: HashDomain) -> bool {
+    follows_key(key, proof) && verify_proof_with::<H>(b"", proof, root, domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_hasher::Blake2sHasher;
+
+    fn key(n: u8) -> Hash {
+        let mut key = [0u8; 32];
+        key[0] = n.wrapping_mul(37);
+        key[31] = n;
+        key
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let tree = SparseMerkleTree::new();
+        assert!(tree.is_empty());
+        assert_eq!(tree.root(), tree.nodes.zero(DEPTH));
+        assert_eq!(tree.get(&key(1)), None);
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.insert(key(1), b"one"), None);
+        assert_eq!(tree.insert(key(2), b"two"), None);
+        assert_eq!(tree.get(&key(1)), Some(&b"one"[..]));
+        assert_eq!(tree.insert(key(1), b"uno"), Some(b"one".to_vec()));
+        assert_eq!(tree.get(&key(1)), Some(&b"uno"[..]));
+        assert_eq!(tree.len(), 2);
+
+        assert_eq!(tree.remove(&key(1)), Some(b"uno".to_vec()));
+        assert_eq!(tree.remove(&key(1)), None);
+        assert!(!tree.contains_key(&key(1)));
+        assert_eq!(tree.len(), 1);
+    }
+
+    #[test]
+    fn test_root_is_order_independent() {
+        let mut tree1 = SparseMerkleTree::new();
+        let mut tree2 = SparseMerkleTree::new();
+        for n in 0..10 {
+            tree1.insert(key(n), &[n]);
+            tree2.insert(key(9 - n), &[9 - n]);
+        }
+        assert_eq!(tree1.root(), tree2.root());
+    }
+
+    #[test]
+    fn test_remove_restores_root_and_storage() {
+        let mut tree = SparseMerkleTree::new();
+        let empty_root = tree.root();
+        tree.insert(key(1), b"one");
+        let one_root = tree.root();
+        tree.insert(key(2), b"two");
+        assert_ne!(tree.root(), one_root);
+
+        tree.remove(&key(2));
+        assert_eq!(tree.root(), one_root);
+        tree.insert(key(1), b"");
+        assert_eq!(tree.root(), empty_root);
+        assert_eq!(tree.nodes.len(), 0, "empty subtrees must not be stored");
+    }
+
+    #[test]
+    fn test_membership_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        for n in 0..8 {
+            tree.insert(key(n), &[n, n]);
+        }
+        let root = tree.root();
+        for n in 0..8 {
+            let proof = tree.proof_path(&key(n));
+            assert_eq!(proof.len(), DEPTH);
+            assert!(verify_membership::<Sha256Hasher>(&key(n), &[n, n], &proof, root, HashDomain::Plain));
+            assert!(!verify_membership::<Sha256Hasher>(&key(n), &[n], &proof, root, HashDomain::Plain));
+            assert!(!verify_non_membership::<Sha256Hasher>(&key(n), &proof, root, HashDomain::Plain));
+            // Same proof, another key: the directions don't match
+            assert!(!verify_membership::<Sha256Hasher>(&key(n + 100), &[n, n], &proof, root, HashDomain::Plain));
+        }
+    }
+
+    #[test]
+    fn test_non_membership_proofs() {
+        let mut tree = SparseMerkleTree::new();
+        for n in 0..8 {
+            tree.insert(key(n), &[n]);
+        }
+        let root = tree.root();
+        let absent = key(42);
+        let proof = tree.proof_path(&absent);
+        assert!(verify_non_membership::<Sha256Hasher>(&absent, &proof, root, HashDomain::Plain));
+        assert!(!verify_non_membership::<Sha256Hasher>(&key(3), &proof, root, HashDomain::Plain));
+
+        // Once inserted, the old absence proof no longer matches the root
+        tree.insert(absent, b"here");
+        assert!(!verify_non_membership::<Sha256Hasher>(&absent, &proof, tree.root(), HashDomain::Plain));
+    }
+
+    #[test]
+    fn test_generic_hasher_and_domain() {
+        let mut tree = SparseMerkleTree::<Blake2sHasher>::build(HashDomain::Rfc6962);
+        tree.insert(key(5), b"five");
+        let proof = tree.proof_path(&key(5));
+        assert!(verify_membership::<Blake2sHasher>(&key(5), b"five", &proof, tree.root(), HashDomain::Rfc6962));
+        let proof = tree.proof_path(&key(6));
+        assert!(verify_non_membership::<Blake2sHasher>(&key(6), &proof, tree.root(), HashDomain::Rfc6962));
+    }
+
+    #[test]
+    fn test_prefix_clears_low_bits() {
+        let key = [0xff; 32];
+        for height in 0..=DEPTH {
+            let prefix = prefix(&key, height);
+            for bit_height in 0..DEPTH {
+                assert_eq!(bit(&prefix, bit_height), bit_height >= height, "height {height}, bit {bit_height}");
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::merkle_hasher::MerkleHasher;
+use crate::merkle_tree::{HashDomain, MerkleNode};
+
+/// Roots of the empty subtrees of height 0 to `depth`, the empty leaf (`b""`) first
+pub(crate) fn zero_hashes<H: MerkleHasher>(depth: usize, domain: HashDomain) -> Vec<H::Digest> {
+    let mut zeros = vec![MerkleNode::<H>::leaf_with(b"", domain).hash];
+    for height in 0..depth {
+        let zero = MerkleNode::<H> { hash: zeros[height] };
+        zeros.push(MerkleNode::parent_with(&zero, &zero, domain).hash);
+    }
+    zeros
+}
+
+/// Nodes of a mostly empty tree of fixed depth, keyed by height and position `K`
+///
+/// Only the nodes that differ from an empty subtree are stored: any other node
+/// reads as `zeros[height]`.
+pub(crate) struct SparseNodes<K, D> {
+    //zeros[h] = root of an empty subtree of height h
+    zeros: Vec<D>,
+    //(height, position) -> non-empty node
+    nodes: HashMap<(usize, K), D>,
+}
+
+impl<K: Eq + Hash, D: Copy + PartialEq> SparseNodes<K, D> {
+    /// Empty tree of the given depth
+    pub(crate) fn new<H: MerkleHasher<Digest = D>>(depth: usize, domain: HashDomain) -> Self {
+        SparseNodes { zeros: zero_hashes::<H>(depth, domain), nodes: HashMap::new() }
+    }
+
+    /// Return the root of an empty subtree of height `height`
+    pub(crate) fn zero(&self, height: usize) -> D {
+        self.zeros[height]
+    }
+
+    pub(crate) fn get(&self, height: usize, position: K) -> D {
+        self.nodes.get(&(height, position)).copied().unwrap_or(self.zeros[height])
+    }
+
+    //empty subtrees are never stored
+    pub(crate) fn set(&mut self, height: usize, position: K, hash: D) {
+        if hash == self.zeros[height] {
+            self.nodes.remove(&(height, position));
+        } else {
+            self.nodes.insert((height, position), hash);
+        }
+    }
+
+    /// Return the number of stored (non-empty) nodes
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.nodes.len()
+    }
+}